@@ -0,0 +1,173 @@
+use config::Config;
+use std::cmp;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AclAction {
+    Refuse,
+    Drop,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Cidr, &'static str> {
+        let mut parts = s.splitn(2, '/');
+        let network: IpAddr = match parts.next().unwrap().parse() {
+            Ok(network) => network,
+            Err(_) => return Err("Invalid network address"),
+        };
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            None => max_prefix_len,
+            Some(prefix_len) => {
+                match prefix_len.parse() {
+                    Ok(prefix_len) if prefix_len <= max_prefix_len => prefix_len,
+                    _ => return Err("Invalid prefix length"),
+                }
+            }
+        };
+        Ok(Cidr {
+            network: network,
+            prefix_len: prefix_len,
+        })
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let prefix_len = prefix_len as usize;
+    let full_octets = prefix_len / 8;
+    if network[..full_octets] != ip[..full_octets] {
+        return false;
+    }
+    let bits = prefix_len % 8;
+    if bits == 0 {
+        return true;
+    }
+    let mask = !(0xffu8 >> bits);
+    network[full_octets] & mask == ip[full_octets] & mask
+}
+
+pub fn client_prefix(client_ip: IpAddr, ipv4_prefix_len: u8, ipv6_prefix_len: u8) -> IpAddr {
+    match client_ip {
+        IpAddr::V4(ip) => {
+            let mut octets = ip.octets();
+            mask_prefix(&mut octets, ipv4_prefix_len);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            mask_prefix(&mut octets, ipv6_prefix_len);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+    }
+}
+
+fn mask_prefix(octets: &mut [u8], prefix_len: u8) {
+    for (i, octet) in octets.iter_mut().enumerate() {
+        let bits = cmp::min(8, (prefix_len as usize).saturating_sub(i * 8));
+        *octet &= !(0xffu16 >> bits) as u8;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Acl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    pub action: AclAction,
+}
+
+impl Acl {
+    pub fn new(config: &Config) -> Acl {
+        Acl {
+            allow: config.acl_allow.clone(),
+            deny: config.acl_deny.clone(),
+            action: config.acl_action,
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_parse() {
+        assert_eq!(cidr("192.0.2.1").prefix_len, 32);
+        assert_eq!(cidr("2001:db8::1").prefix_len, 128);
+        assert_eq!(cidr("0.0.0.0/0").prefix_len, 0);
+        assert_eq!(cidr("192.0.2.1/32").prefix_len, 32);
+        assert_eq!(cidr("::/0").prefix_len, 0);
+        assert_eq!(cidr("2001:db8::1/128").prefix_len, 128);
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/-1".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/24/8".parse::<Cidr>().is_err());
+        assert!("example.com/24".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("203.0.113.7")));
+        assert!(cidr("192.0.2.1/32").contains(ip("192.0.2.1")));
+        assert!(!cidr("192.0.2.1/32").contains(ip("192.0.2.2")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+        assert!(cidr("192.0.2.0/25").contains(ip("192.0.2.127")));
+        assert!(!cidr("192.0.2.0/25").contains(ip("192.0.2.128")));
+        assert!(cidr("2001:db8::/33").contains(ip("2001:db8:7fff::1")));
+        assert!(!cidr("2001:db8::/33").contains(ip("2001:db8:8000::1")));
+    }
+
+    #[test]
+    fn test_client_prefix() {
+        assert_eq!(client_prefix(ip("203.0.113.7"), 24, 56), ip("203.0.113.0"));
+        assert_eq!(client_prefix(ip("203.0.113.7"), 0, 56), ip("0.0.0.0"));
+        assert_eq!(client_prefix(ip("203.0.113.7"), 32, 56), ip("203.0.113.7"));
+        assert_eq!(client_prefix(ip("203.0.113.255"), 25, 56), ip("203.0.113.128"));
+        assert_eq!(client_prefix(ip("2001:db8:1:2ff::1"), 24, 56), ip("2001:db8:1:200::"));
+        assert_eq!(client_prefix(ip("2001:db8::1"), 24, 0), ip("::"));
+        assert_eq!(client_prefix(ip("2001:db8::1"), 24, 128), ip("2001:db8::1"));
+    }
+}
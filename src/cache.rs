@@ -2,12 +2,22 @@ use config::Config;
 use clockpro_cache::*;
 use dns;
 use dns::{NormalizedQuestion, NormalizedQuestionKey, DNS_CLASS_IN, DNS_RCODE_NXDOMAIN};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::SERVE_STALE_TTL;
+
+const SNAPSHOT_MAGIC: &'static [u8; 8] = b"EDNSSNP1";
 
 #[derive(Clone, Debug)]
 pub struct CacheEntry {
     pub expiration: Instant,
+    pub ttl: u32,
+    pub hits: u32,
     pub packet: Vec<u8>,
 }
 
@@ -18,10 +28,42 @@ impl CacheEntry {
     }
 }
 
+struct CacheSlot {
+    entry: Arc<CacheEntry>,
+    hits: u32,
+}
+
+impl CacheSlot {
+    fn cache_entry(&self) -> CacheEntry {
+        let mut cache_entry = (*self.entry).clone();
+        cache_entry.hits = self.hits;
+        cache_entry
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheDecision {
+    Hit,
+    Prefetch,
+    Stale,
+    Expired,
+}
+
+impl CacheDecision {
+    pub fn is_servable(self) -> bool {
+        self != CacheDecision::Expired
+    }
+
+    pub fn needs_refresh(self) -> bool {
+        self == CacheDecision::Prefetch || self == CacheDecision::Stale
+    }
+}
+
 #[derive(Clone)]
 pub struct Cache {
-    config: Config,
-    arc_mx: Arc<Mutex<ClockProCache<NormalizedQuestionKey, CacheEntry>>>,
+    config: Arc<RwLock<Config>>,
+    arc_mx: Arc<Mutex<ClockProCache<NormalizedQuestionKey, CacheSlot>>>,
+    snapshot_index_mx: Option<Arc<Mutex<HashMap<NormalizedQuestionKey, Weak<CacheEntry>>>>>,
 }
 
 pub struct CacheStats {
@@ -36,12 +78,24 @@ impl Cache {
     pub fn new(config: Config) -> Cache {
         let arc = ClockProCache::new(config.cache_size).unwrap();
         let arc_mx = Arc::new(Mutex::new(arc));
+        let snapshot_index_mx = config.cache_snapshot_file
+            .as_ref()
+            .map(|_| Arc::new(Mutex::new(HashMap::new())));
         Cache {
-            config: config,
+            config: Arc::new(RwLock::new(config)),
             arc_mx: arc_mx,
+            snapshot_index_mx: snapshot_index_mx,
         }
     }
 
+    pub fn reload(&self, config: Config) {
+        *self.config.write().unwrap() = config;
+    }
+
+    fn config(&self) -> RwLockReadGuard<Config> {
+        self.config.read().unwrap()
+    }
+
     pub fn stats(&self) -> CacheStats {
         let cache = self.arc_mx.lock().unwrap();
         CacheStats {
@@ -65,39 +119,155 @@ impl Cache {
         let now = Instant::now();
         let duration = Duration::from_secs(ttl as u64);
         let expiration = now + duration;
-        let mut cache = self.arc_mx.lock().unwrap();
         let cache_entry = CacheEntry {
             expiration: expiration,
+            ttl: ttl,
+            hits: 0,
             packet: packet,
         };
-        cache.insert(normalized_question_key, cache_entry)
+        self.insert_entry(normalized_question_key, cache_entry)
+    }
+
+    fn insert_entry(&mut self,
+                    normalized_question_key: NormalizedQuestionKey,
+                    cache_entry: CacheEntry)
+                    -> bool {
+        let cache_size = self.config().cache_size;
+        let cache_entry = Arc::new(cache_entry);
+        let mut cache = self.arc_mx.lock().unwrap();
+        if let Some(ref snapshot_index_mx) = self.snapshot_index_mx {
+            let mut snapshot_index = snapshot_index_mx.lock().unwrap();
+            if snapshot_index.len() >= cache_size * 2 {
+                snapshot_index.retain(|_, cache_entry| cache_entry.upgrade().is_some());
+            }
+            snapshot_index.insert(normalized_question_key.clone(), Arc::downgrade(&cache_entry));
+        }
+        let cache_slot = CacheSlot {
+            entry: cache_entry,
+            hits: 0,
+        };
+        cache.insert(normalized_question_key, cache_slot)
+    }
+
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let snapshot_index_mx = match self.snapshot_index_mx {
+            None => return Ok(0),
+            Some(ref snapshot_index_mx) => snapshot_index_mx,
+        };
+        let entries: Vec<(NormalizedQuestionKey, Arc<CacheEntry>)> = {
+            let mut snapshot_index = snapshot_index_mx.lock().unwrap();
+            snapshot_index.retain(|_, cache_entry| cache_entry.upgrade().is_some());
+            snapshot_index.iter()
+                .filter_map(|(key, cache_entry)| {
+                    cache_entry.upgrade().map(|cache_entry| (key.clone(), cache_entry))
+                })
+                .collect()
+        };
+        let now = Instant::now();
+        let now_unix = unix_now();
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut count = 0;
+        {
+            let mut writer = BufWriter::new(try!(File::create(&tmp_path)));
+            try!(writer.write_all(SNAPSHOT_MAGIC));
+            for (key, cache_entry) in entries {
+                if now >= cache_entry.expiration || key.qname_lc.len() > 0xffff ||
+                   cache_entry.packet.len() > 0xffff {
+                    continue;
+                }
+                let expiration_unix = now_unix +
+                                      cache_entry.expiration.duration_since(now).as_secs();
+                try!(write_u16(&mut writer, key.qname_lc.len() as u16));
+                try!(writer.write_all(&key.qname_lc));
+                try!(write_u16(&mut writer, key.qtype));
+                try!(write_u16(&mut writer, key.qclass));
+                try!(writer.write_all(&[key.dnssec as u8]));
+                try!(write_u64(&mut writer, expiration_unix));
+                try!(write_u32(&mut writer, cache_entry.ttl));
+                try!(write_u16(&mut writer, cache_entry.packet.len() as u16));
+                try!(writer.write_all(&cache_entry.packet));
+                count += 1;
+            }
+            try!(writer.flush());
+        }
+        try!(fs::rename(&tmp_path, path));
+        Ok(count)
+    }
+
+    pub fn load_snapshot<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        let mut reader = BufReader::new(try!(File::open(path)));
+        let mut magic = [0u8; 8];
+        try!(reader.read_exact(&mut magic));
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Not a cache snapshot, or unsupported version"));
+        }
+        let now = Instant::now();
+        let now_unix = unix_now();
+        let mut count = 0;
+        loop {
+            let (key, expiration_unix, ttl, packet) = match read_snapshot_entry(&mut reader) {
+                Ok(None) => break,
+                Ok(Some(entry)) => entry,
+                Err(e) => {
+                    warn!("Truncated cache snapshot: {}", e);
+                    break;
+                }
+            };
+            if expiration_unix <= now_unix {
+                continue;
+            }
+            if packet.len() < dns::DNS_HEADER_SIZE || dns::normalize(&packet, false).is_err() {
+                debug!("Skipping an unparsable entry from the cache snapshot");
+                continue;
+            }
+            let cache_entry = CacheEntry {
+                expiration: now + Duration::from_secs(expiration_unix - now_unix),
+                ttl: ttl,
+                hits: 0,
+                packet: packet,
+            };
+            self.insert_entry(key, cache_entry);
+            count += 1;
+        }
+        Ok(count)
     }
 
     pub fn get(&mut self, normalized_question_key: &NormalizedQuestionKey) -> Option<CacheEntry> {
         let mut cache = self.arc_mx.lock().unwrap();
-        cache.get_mut(normalized_question_key).and_then(|res| Some(res.clone()))
+        cache.get(normalized_question_key).map(|cache_slot| cache_slot.cache_entry())
     }
 
     pub fn get2(&mut self, normalized_question: &NormalizedQuestion) -> Option<CacheEntry> {
+        let (max_ttl, decrement_ttl) = {
+            let config = self.config();
+            (config.max_ttl, config.decrement_ttl)
+        };
         if let Some(special_packet) = self.handle_special_queries(normalized_question) {
             Some(CacheEntry {
-                expiration: Instant::now() + Duration::from_secs(self.config.max_ttl as u64),
+                expiration: Instant::now() + Duration::from_secs(max_ttl as u64),
+                ttl: max_ttl,
+                hits: 0,
                 packet: special_packet,
             })
         } else if normalized_question.qclass != DNS_CLASS_IN {
             Some(CacheEntry {
-                expiration: Instant::now() + Duration::from_secs(self.config.max_ttl as u64),
+                expiration: Instant::now() + Duration::from_secs(max_ttl as u64),
+                ttl: max_ttl,
+                hits: 0,
                 packet: dns::build_refused_packet(normalized_question).unwrap(),
             })
         } else {
             let normalized_question_key = normalized_question.key();
-            let cache_entry = self.get(&normalized_question_key);
+            let cache_entry = self.hit(&normalized_question_key);
             if let Some(mut cache_entry) = cache_entry {
-                if self.config.decrement_ttl {
+                if decrement_ttl {
                     let now = Instant::now();
                     if now <= cache_entry.expiration {
                         let remaining_ttl = cache_entry.expiration.duration_since(now).as_secs();
-                        let _ = dns::set_ttl(&mut cache_entry.packet, remaining_ttl as u32);
+                        let elapsed = cache_entry.ttl.saturating_sub(remaining_ttl as u32);
+                        let _ = dns::decrement_ttls(&mut cache_entry.packet, elapsed);
                     }
                 }
                 return Some(cache_entry);
@@ -116,6 +286,8 @@ impl Cache {
                             debug!("Shifted query returned NXDOMAIN");
                             return Some(CacheEntry {
                                 expiration: shifted_cache_entry.expiration,
+                                ttl: shifted_cache_entry.ttl,
+                                hits: 0,
                                 packet: dns::build_nxdomain_packet(normalized_question).unwrap(),
                             });
                         }
@@ -126,20 +298,151 @@ impl Cache {
         }
     }
 
+    pub fn lookup(&mut self,
+                  normalized_question: &NormalizedQuestion)
+                  -> Option<(CacheEntry, CacheDecision)> {
+        let mut cache_entry = match self.get2(normalized_question) {
+            None => return None,
+            Some(cache_entry) => cache_entry,
+        };
+        let cache_decision = if !cache_entry.is_expired() {
+            if self.should_prefetch(&cache_entry) {
+                CacheDecision::Prefetch
+            } else {
+                CacheDecision::Hit
+            }
+        } else if self.serve_stale(&mut cache_entry) {
+            CacheDecision::Stale
+        } else {
+            CacheDecision::Expired
+        };
+        Some((cache_entry, cache_decision))
+    }
+
+    fn hit(&self, normalized_question_key: &NormalizedQuestionKey) -> Option<CacheEntry> {
+        let mut cache = self.arc_mx.lock().unwrap();
+        let cache_slot = match cache.get_mut(normalized_question_key) {
+            None => return None,
+            Some(cache_slot) => cache_slot,
+        };
+        cache_slot.hits = cache_slot.hits.saturating_add(1);
+        let res = cache_slot.cache_entry();
+        if self.should_prefetch(&res) {
+            cache_slot.hits = 0;
+        }
+        Some(res)
+    }
+
+    fn should_prefetch(&self, cache_entry: &CacheEntry) -> bool {
+        let config = self.config();
+        if config.prefetch_threshold == 0 || cache_entry.hits < config.prefetch_min_hits {
+            return false;
+        }
+        let now = Instant::now();
+        if now > cache_entry.expiration {
+            return false;
+        }
+        let remaining_ttl = cache_entry.expiration.duration_since(now).as_secs();
+        remaining_ttl * 100 <= cache_entry.ttl as u64 * config.prefetch_threshold as u64
+    }
+
+    pub fn serve_stale(&self, cache_entry: &mut CacheEntry) -> bool {
+        if !cache_entry.is_expired() {
+            return true;
+        }
+        let serve_stale_max = self.config().serve_stale_max;
+        if serve_stale_max == 0 {
+            return false;
+        }
+        let stale_deadline = cache_entry.expiration +
+                             Duration::from_secs(serve_stale_max as u64);
+        if Instant::now() > stale_deadline {
+            return false;
+        }
+        let _ = dns::set_ttl(&mut cache_entry.packet, SERVE_STALE_TTL);
+        true
+    }
+
     fn handle_special_queries(&self, normalized_question: &NormalizedQuestion) -> Option<Vec<u8>> {
         if normalized_question.qclass == dns::DNS_CLASS_IN &&
            normalized_question.qtype == dns::DNS_TYPE_ANY {
             debug!("ANY query");
-            let packet = dns::build_any_packet(normalized_question, self.config.max_ttl).unwrap();
+            let packet = dns::build_any_packet(normalized_question, self.config().max_ttl)
+                .unwrap();
             return Some(packet);
         }
         if normalized_question.qclass == dns::DNS_CLASS_CH &&
            normalized_question.qtype == dns::DNS_TYPE_TXT {
             debug!("CHAOS TXT");
-            let packet = dns::build_version_packet(normalized_question, self.config.max_ttl)
+            let packet = dns::build_version_packet(normalized_question, self.config().max_ttl)
                 .unwrap();
             return Some(packet);
         }
         None
     }
 }
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+}
+
+fn write_u16<W: Write>(writer: &mut W, x: u16) -> io::Result<()> {
+    writer.write_all(&[(x >> 8) as u8, x as u8])
+}
+
+fn write_u32<W: Write>(writer: &mut W, x: u32) -> io::Result<()> {
+    try!(write_u16(writer, (x >> 16) as u16));
+    write_u16(writer, x as u16)
+}
+
+fn write_u64<W: Write>(writer: &mut W, x: u64) -> io::Result<()> {
+    try!(write_u32(writer, (x >> 32) as u32));
+    write_u32(writer, x as u32)
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    try!(reader.read_exact(&mut buf));
+    Ok(((buf[0] as u16) << 8) | buf[1] as u16)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let hi = try!(read_u16(reader)) as u32;
+    let lo = try!(read_u16(reader)) as u32;
+    Ok((hi << 16) | lo)
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let hi = try!(read_u32(reader)) as u64;
+    let lo = try!(read_u32(reader)) as u64;
+    Ok((hi << 32) | lo)
+}
+
+fn read_snapshot_entry<R: Read>(reader: &mut R)
+                                -> io::Result<Option<(NormalizedQuestionKey, u64, u32, Vec<u8>)>> {
+    let mut first = [0u8; 1];
+    if try!(reader.read(&mut first)) == 0 {
+        return Ok(None);
+    }
+    let mut second = [0u8; 1];
+    try!(reader.read_exact(&mut second));
+    let qname_len = ((first[0] as usize) << 8) | second[0] as usize;
+    let mut qname_lc = vec![0u8; qname_len];
+    try!(reader.read_exact(&mut qname_lc));
+    let qtype = try!(read_u16(reader));
+    let qclass = try!(read_u16(reader));
+    let mut dnssec = [0u8; 1];
+    try!(reader.read_exact(&mut dnssec));
+    let expiration_unix = try!(read_u64(reader));
+    let ttl = try!(read_u32(reader));
+    let packet_len = try!(read_u16(reader)) as usize;
+    let mut packet = vec![0u8; packet_len];
+    try!(reader.read_exact(&mut packet));
+    let key = NormalizedQuestionKey {
+        qname_lc: qname_lc,
+        qtype: qtype,
+        qclass: qclass,
+        dnssec: dnssec[0] != 0,
+    };
+    Ok(Some((key, expiration_unix, ttl, packet)))
+}
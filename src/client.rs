@@ -1,33 +1,224 @@
-use bytes::SliceBuf;
 use dns::NormalizedQuestion;
 use mio::*;
 use mio::timer::Timeout;
+use rustls::{ServerSession, Session};
+use std::io;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::time::Instant;
 
-use super::DNS_QUERY_MAX_SIZE;
+use super::{DNS_QUERY_MAX_SIZE, DNS_QUERY_MIN_SIZE, MAX_TCP_PIPELINED_QUERIES};
 use tcp_listener::TCP_QUERY_HEADER_SIZE;
 
+const CLIENT_READ_BUF_SIZE: usize = (TCP_QUERY_HEADER_SIZE + DNS_QUERY_MAX_SIZE) *
+                                    MAX_TCP_PIPELINED_QUERIES;
+
+pub struct PendingQuestion {
+    pub normalized_question: NormalizedQuestion,
+    pub deadline: Instant,
+}
+
 pub struct Client {
-    pub normalized_question: Option<NormalizedQuestion>,
+    pub peer_ip: IpAddr,
+    pub pending_questions: Vec<PendingQuestion>,
     pub tcp_stream: tcp::TcpStream,
-    pub read_buf: SliceBuf<Vec<u8>>,
-    pub expected_len: Option<u16>,
+    pub tls_session: Option<ServerSession>,
+    pub read_buf: Vec<u8>,
+    pub write_buf: Vec<u8>,
     pub interest: Ready,
     pub timeout: Option<Timeout>,
-    pub resolving: bool,
+    pub eof: bool,
     pub attic: bool,
+    pub refused: bool,
 }
 
 impl Client {
-    pub fn new(tcp_stream: tcp::TcpStream) -> Client {
+    pub fn new(peer_ip: IpAddr,
+               tcp_stream: tcp::TcpStream,
+               tls_session: Option<ServerSession>)
+               -> Client {
         Client {
-            normalized_question: None,
+            peer_ip: peer_ip,
+            pending_questions: Vec::with_capacity(MAX_TCP_PIPELINED_QUERIES),
             tcp_stream: tcp_stream,
-            read_buf: SliceBuf::new(vec![0u8; TCP_QUERY_HEADER_SIZE + DNS_QUERY_MAX_SIZE]),
-            expected_len: None,
+            tls_session: tls_session,
+            read_buf: Vec::with_capacity(TCP_QUERY_HEADER_SIZE + DNS_QUERY_MAX_SIZE),
+            write_buf: Vec::new(),
             interest: Ready::hup() | Ready::error(),
             timeout: None,
-            resolving: false,
+            eof: false,
             attic: false,
+            refused: false,
+        }
+    }
+
+    pub fn fill_read_buf(&mut self) -> io::Result<()> {
+        if self.tls_session.is_some() {
+            return self.fill_read_buf_tls();
+        }
+        let mut chunk = [0u8; TCP_QUERY_HEADER_SIZE + DNS_QUERY_MAX_SIZE];
+        while self.read_buf.len() < CLIENT_READ_BUF_SIZE {
+            match self.tcp_stream.read(&mut chunk) {
+                Ok(0) => {
+                    debug!("Client socket is closed; nothing to read");
+                    self.eof = true;
+                    break;
+                }
+                Ok(count) => {
+                    debug!("Client socket got {} bytes", count);
+                    self.read_buf.extend_from_slice(&chunk[..count]);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    debug!("Client socket is empty");
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_read_buf_tls(&mut self) -> io::Result<()> {
+        let tls_session = self.tls_session.as_mut().expect("TLS session expected");
+        while self.read_buf.len() < CLIENT_READ_BUF_SIZE {
+            match tls_session.read_tls(&mut self.tcp_stream) {
+                Ok(0) => {
+                    debug!("TLS client socket is closed; nothing to read");
+                    self.eof = true;
+                    break;
+                }
+                Ok(count) => debug!("TLS client socket got {} bytes", count),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    debug!("TLS client socket is empty");
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+            if let Err(e) = tls_session.process_new_packets() {
+                info!("TLS error: {:?}", e);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "TLS error"));
+            }
+            try!(tls_session.read_to_end(&mut self.read_buf));
+        }
+        Ok(())
+    }
+
+    pub fn next_query(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+        let bytes_len = self.read_buf.len();
+        if bytes_len < TCP_QUERY_HEADER_SIZE {
+            return Ok(None);
+        }
+        let expected_len = ((self.read_buf[0] as usize) << 8) | self.read_buf[1] as usize;
+        if expected_len < DNS_QUERY_MIN_SIZE || expected_len > DNS_QUERY_MAX_SIZE {
+            return Err("Suspicious query length");
+        }
+        if bytes_len < TCP_QUERY_HEADER_SIZE + expected_len {
+            debug!("Partial query");
+            return Ok(None);
+        }
+        let packet = self.read_buf[TCP_QUERY_HEADER_SIZE..TCP_QUERY_HEADER_SIZE + expected_len]
+            .to_vec();
+        self.read_buf.drain(..TCP_QUERY_HEADER_SIZE + expected_len);
+        Ok(Some(packet))
+    }
+
+    pub fn queue_response(&mut self, packet: &[u8]) {
+        let packet_len = packet.len();
+        if let Some(ref mut tls_session) = self.tls_session {
+            let binlen = [(packet_len >> 8) as u8, packet_len as u8];
+            let _ = tls_session.write_all(&binlen);
+            let _ = tls_session.write_all(packet);
+            return;
+        }
+        self.write_buf.reserve(TCP_QUERY_HEADER_SIZE + packet_len);
+        self.write_buf.push((packet_len >> 8) as u8);
+        self.write_buf.push(packet_len as u8);
+        self.write_buf.extend_from_slice(packet);
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.tls_session.is_some() {
+            let res = self.flush_tls();
+            self.update_interest();
+            return res;
+        }
+        let mut written = 0;
+        let mut res = Ok(());
+        while written < self.write_buf.len() {
+            match self.tcp_stream.write(&self.write_buf[written..]) {
+                Ok(0) => {
+                    res = Err(io::Error::new(io::ErrorKind::WriteZero,
+                                             "Client closed the connection"));
+                    break;
+                }
+                Ok(count) => written += count,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+        self.write_buf.drain(..written);
+        self.update_interest();
+        res
+    }
+
+    fn flush_tls(&mut self) -> io::Result<()> {
+        let tls_session = self.tls_session.as_mut().expect("TLS session expected");
+        while tls_session.wants_write() {
+            match tls_session.write_tls(&mut self.tcp_stream) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "Client closed the connection"))
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn wants_write(&self) -> bool {
+        match self.tls_session {
+            None => !self.write_buf.is_empty(),
+            Some(ref tls_session) => tls_session.wants_write(),
+        }
+    }
+
+    pub fn update_interest(&mut self) {
+        let mut interest = Ready::hup() | Ready::error();
+        if self.eof {
+            interest = Ready::error();
+        } else if self.read_buf.len() < CLIENT_READ_BUF_SIZE &&
+                  self.pending_questions.len() < MAX_TCP_PIPELINED_QUERIES {
+            interest.insert(Ready::readable());
+        }
+        if self.wants_write() {
+            interest.insert(Ready::writable());
+        }
+        self.interest = interest;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pending_questions.is_empty() && !self.wants_write()
+    }
+
+    pub fn oldest_deadline(&self) -> Option<Instant> {
+        self.pending_questions.iter().map(|pending_question| pending_question.deadline).min()
+    }
+
+    pub fn take_expired_questions(&mut self, now: Instant) -> Vec<NormalizedQuestion> {
+        let mut expired = Vec::new();
+        let mut i = 0;
+        while i < self.pending_questions.len() {
+            if self.pending_questions[i].deadline <= now {
+                expired.push(self.pending_questions.swap_remove(i).normalized_question);
+            } else {
+                i += 1;
+            }
         }
+        expired
     }
 }
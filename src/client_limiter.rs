@@ -0,0 +1,113 @@
+use acl::client_prefix;
+use clockpro_cache::*;
+use config::Config;
+use siphasher::sip::SipHasher13;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use token_bucket::TokenBucket;
+use varz::Varz;
+
+use super::{CLIENT_LIMITER_SHARDS, CLIENT_LIMITER_TABLE_SIZE};
+
+#[derive(Clone)]
+pub struct ClientLimiter {
+    max_queries_per_second: u32,
+    max_tcp_connections: usize,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
+    queries_shards: Arc<Vec<Mutex<ClockProCache<IpAddr, TokenBucket>>>>,
+    tcp_connections_mx: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    varz: Arc<Varz>,
+}
+
+impl ClientLimiter {
+    pub fn new(config: &Config, varz: Arc<Varz>) -> ClientLimiter {
+        let queries_shards = (0..CLIENT_LIMITER_SHARDS)
+            .map(|_| {
+                Mutex::new(ClockProCache::new(CLIENT_LIMITER_TABLE_SIZE / CLIENT_LIMITER_SHARDS)
+                    .unwrap())
+            })
+            .collect();
+        ClientLimiter {
+            max_queries_per_second: config.client_max_queries_per_second,
+            max_tcp_connections: config.client_max_tcp_connections,
+            ipv4_prefix_len: config.client_ipv4_prefix_len,
+            ipv6_prefix_len: config.client_ipv6_prefix_len,
+            queries_shards: Arc::new(queries_shards),
+            tcp_connections_mx: Arc::new(Mutex::new(HashMap::new())),
+            varz: varz,
+        }
+    }
+
+    /// Returns `false` if the client network exceeded its query rate.
+    pub fn query_allowed(&self, client_ip: IpAddr) -> bool {
+        if self.max_queries_per_second == 0 {
+            return true;
+        }
+        let client_prefix = self.client_prefix(client_ip);
+        let mut hs = SipHasher13::new();
+        client_prefix.hash(&mut hs);
+        let shard = &self.queries_shards[hs.finish() as usize % CLIENT_LIMITER_SHARDS];
+        let allowed = {
+            let mut queries = shard.lock().unwrap();
+            let now = Instant::now();
+            let allowed = queries.get_mut(&client_prefix)
+                .map(|token_bucket| token_bucket.take(self.max_queries_per_second, now));
+            match allowed {
+                Some(allowed) => allowed,
+                None => {
+                    let mut token_bucket = TokenBucket::new(self.max_queries_per_second, now);
+                    token_bucket.take(self.max_queries_per_second, now);
+                    queries.insert(client_prefix, token_bucket);
+                    true
+                }
+            }
+        };
+        if !allowed {
+            debug!("Query rate limit reached for {}", client_ip);
+            self.varz.client_queries_rate_limited.inc();
+        }
+        allowed
+    }
+
+    pub fn tcp_connection_opened(&self, client_ip: IpAddr) -> bool {
+        if self.max_tcp_connections == 0 {
+            return true;
+        }
+        let client_prefix = self.client_prefix(client_ip);
+        let mut tcp_connections = self.tcp_connections_mx.lock().unwrap();
+        let count = tcp_connections.entry(client_prefix).or_insert(0);
+        if *count >= self.max_tcp_connections {
+            debug!("TCP connections limit reached for {}", client_ip);
+            self.varz.client_tcp_connections_rejected.inc();
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    pub fn tcp_connection_closed(&self, client_ip: IpAddr) {
+        if self.max_tcp_connections == 0 {
+            return;
+        }
+        let client_prefix = self.client_prefix(client_ip);
+        let mut tcp_connections = self.tcp_connections_mx.lock().unwrap();
+        let remove = match tcp_connections.get_mut(&client_prefix) {
+            None => return,
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+        };
+        if remove {
+            tcp_connections.remove(&client_prefix);
+        }
+    }
+
+    fn client_prefix(&self, client_ip: IpAddr) -> IpAddr {
+        client_prefix(client_ip, self.ipv4_prefix_len, self.ipv6_prefix_len)
+    }
+}
@@ -8,14 +8,44 @@ use std::time::Instant;
 pub enum ClientQueryProtocol {
     UDP,
     TCP,
+    TLS,
+    HTTPS,
+    Refresh,
+}
+
+impl ClientQueryProtocol {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ClientQueryProtocol::UDP => "udp",
+            ClientQueryProtocol::TCP => "tcp",
+            ClientQueryProtocol::TLS => "tls",
+            ClientQueryProtocol::HTTPS => "https",
+            ClientQueryProtocol::Refresh => "refresh",
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct ClientQuery {
     pub proto: ClientQueryProtocol,
     pub client_addr: Option<SocketAddr>,
+    pub udp_socket_idx: Option<usize>,
     pub tcpclient_tx: Option<channel::SyncSender<ResolverResponse>>,
     pub client_tok: Option<Token>,
     pub normalized_question: NormalizedQuestion,
     pub ts: Instant,
 }
+
+impl ClientQuery {
+    pub fn refresh(normalized_question: &NormalizedQuestion) -> ClientQuery {
+        ClientQuery {
+            proto: ClientQueryProtocol::Refresh,
+            client_addr: None,
+            udp_socket_idx: None,
+            tcpclient_tx: None,
+            client_tok: None,
+            normalized_question: normalized_question.clone(),
+            ts: Instant::now(),
+        }
+    }
+}
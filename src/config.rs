@@ -1,139 +1,733 @@
+use acl::{AclAction, Cidr};
+use std::error;
+use std::fmt;
 use std::io::prelude::*;
 use std::fs::File;
-use std::io::{Error, ErrorKind};
+use std::io;
+use std::net::SocketAddr;
 use std::path::Path;
 use toml;
+use upstream::UpstreamAddr;
 
-#[derive(Clone, Debug)]
+const KNOWN_KEYS: &'static [(&'static str, &'static [&'static str])] =
+    &[("upstream",
+       &["type",
+         "servers",
+         "strategy",
+         "max_failures",
+         "initial_timeout_ms",
+         "max_timeout_ms",
+         "health_check_ms"]),
+      ("cache",
+       &["max_items",
+         "min_ttl",
+         "max_ttl",
+         "negative_min_ttl",
+         "negative_max_ttl",
+         "serve_stale_max",
+         "prefetch_threshold",
+         "prefetch_min_hits",
+         "snapshot_file",
+         "snapshot_interval",
+         "failure_ttl"]),
+      ("network",
+       &["udp_ports",
+         "udp_listener_threads",
+         "listen",
+         "udp_buffer_size",
+         "max_active_queries",
+         "max_tcp_clients",
+         "max_tcp_idle_ms"]),
+      ("webservice", &["enabled", "listen"]),
+      ("tls", &["enabled", "listen", "cert_file", "key_file"]),
+      ("rrl", &["responses_per_second", "slip", "log_only", "ipv4_prefix_len", "ipv6_prefix_len"]),
+      ("acl", &["allow", "deny", "action"]),
+      ("client_limits",
+       &["queries_per_second", "tcp_connections", "ipv4_prefix_len", "ipv6_prefix_len"]),
+      ("heavy_hitters", &["enabled", "top", "capacity", "decay_window"]),
+      ("query_log", &["enabled", "file", "socket", "max_file_size", "max_files", "sample_rate"]),
+      ("global", &["user", "group", "chroot_dir", "shutdown_timeout"])];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Syntax(String),
+    MissingKey(&'static str),
+    UnknownKey(String),
+    InvalidType {
+        key: &'static str,
+        expected: &'static str,
+    },
+    InvalidValue { key: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "{}", e),
+            ConfigError::Syntax(ref e) => write!(f, "Syntax error - {}", e),
+            ConfigError::MissingKey(key) => write!(f, "{} is required", key),
+            ConfigError::UnknownKey(ref key) => write!(f, "Unknown setting: {}", key),
+            ConfigError::InvalidType { key, expected } => {
+                write!(f, "{} must be {}", key, expected)
+            }
+            ConfigError::InvalidValue { key, ref reason } => {
+                write!(f, "Invalid value for {}: {}", key, reason)
+            }
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Io(ref e) => error::Error::description(e),
+            ConfigError::Syntax(_) => "syntax error",
+            ConfigError::MissingKey(_) => "missing setting",
+            ConfigError::UnknownKey(_) => "unknown setting",
+            ConfigError::InvalidType { .. } => "invalid type",
+            ConfigError::InvalidValue { .. } => "invalid value",
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Config {
     pub decrement_ttl: bool,
-    pub upstream_servers: Vec<String>,
+    pub upstream_servers: Vec<UpstreamAddr>,
     pub failover: bool,
     pub upstream_max_failures: u32,
+    pub upstream_initial_timeout_ms: u64,
+    pub upstream_max_timeout_ms: u64,
+    pub health_check_ms: u64,
     pub cache_size: usize,
     pub udp_ports: u16,
-    pub listen_addr: String,
+    pub udp_listener_threads: usize,
+    pub listen_addrs: Vec<String>,
+    pub udp_buffer_size: usize,
+    pub max_active_queries: usize,
+    pub max_tcp_clients: usize,
+    pub max_tcp_idle_ms: u64,
     pub webservice_enabled: bool,
     pub webservice_listen_addr: String,
+    pub tls_enabled: bool,
+    pub tls_listen_addr: String,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
     pub min_ttl: u32,
     pub max_ttl: u32,
+    pub negative_min_ttl: u32,
+    pub negative_max_ttl: u32,
+    pub serve_stale_max: u32,
+    pub prefetch_threshold: u32,
+    pub prefetch_min_hits: u32,
+    pub cache_snapshot_file: Option<String>,
+    pub cache_snapshot_interval: u64,
+    pub failure_ttl: u32,
+    pub rrl_responses_per_second: u32,
+    pub rrl_slip: u32,
+    pub rrl_log_only: bool,
+    pub rrl_ipv4_prefix_len: u8,
+    pub rrl_ipv6_prefix_len: u8,
+    pub acl_allow: Vec<Cidr>,
+    pub acl_deny: Vec<Cidr>,
+    pub acl_action: AclAction,
+    pub client_max_queries_per_second: u32,
+    pub client_max_tcp_connections: usize,
+    pub client_ipv4_prefix_len: u8,
+    pub client_ipv6_prefix_len: u8,
+    pub heavy_hitters_enabled: bool,
+    pub heavy_hitters_top: usize,
+    pub heavy_hitters_capacity: usize,
+    pub heavy_hitters_decay_window: u64,
+    pub query_log_enabled: bool,
+    pub query_log_file: Option<String>,
+    pub query_log_socket: Option<String>,
+    pub query_log_max_file_size: u64,
+    pub query_log_max_files: usize,
+    pub query_log_sample_rate: u32,
     pub user: Option<String>,
     pub group: Option<String>,
     pub chroot_dir: Option<String>,
+    pub shutdown_timeout: u64,
 }
 
 impl Config {
-    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
         let mut fd = try!(File::open(path));
         let mut toml = String::new();
         try!(fd.read_to_string(&mut toml));
         Self::from_string(&toml)
     }
 
-    pub fn from_string(toml: &str) -> Result<Config, Error> {
-        let toml_config = match toml.parse() {
-            Ok(toml_config) => toml_config,
-            Err(_) => {
-                return Err(Error::new(ErrorKind::InvalidData,
-                                      "Syntax error - config file is not valid TOML"))
+    /// Returns the path to use for `path` once the process has been chrooted.
+    /// Paths located inside `chroot_dir` are made relative to its root; other
+    /// paths are returned unchanged.
+    pub fn chrooted_path(&self, path: &str) -> String {
+        let chroot_dir = match self.chroot_dir {
+            None => return path.to_owned(),
+            Some(ref chroot_dir) => chroot_dir,
+        };
+        match Path::new(path).strip_prefix(chroot_dir) {
+            Err(_) => path.to_owned(),
+            Ok(relative_path) => Path::new("/").join(relative_path).to_string_lossy().into_owned(),
+        }
+    }
+
+    pub fn from_string(toml: &str) -> Result<Config, ConfigError> {
+        let mut parser = toml::Parser::new(toml);
+        let toml_config = match parser.parse() {
+            Some(toml_config) => toml::Value::Table(toml_config),
+            None => {
+                let errors: Vec<String> = parser.errors
+                    .iter()
+                    .map(|e| {
+                        let (line, col) = parser.to_linecol(e.lo);
+                        format!("line {}, column {}: {}", line + 1, col + 1, e.desc)
+                    })
+                    .collect();
+                return Err(ConfigError::Syntax(errors.join("; ")));
             }
         };
+        try!(check_unknown_keys(&toml_config));
         Self::parse(toml_config)
     }
 
-    fn parse(toml_config: toml::Value) -> Result<Config, Error> {
-        let decrement_ttl_str = toml_config.lookup("upstream.type").map_or("authoritative", |x| {
-            x.as_str().expect("upstream.type must be a string")
-        });
+    fn parse(toml_config: toml::Value) -> Result<Config, ConfigError> {
+        let decrement_ttl_str = try!(get_str(&toml_config, "upstream.type"))
+            .unwrap_or("authoritative");
         let decrement_ttl = match decrement_ttl_str {
             "authoritative" => false,
             "resolver" => true,
             _ => {
-                return Err(Error::new(ErrorKind::InvalidData,
-                                      "Invalid value for the type of upstream servers. Must be \
-                                       'authoritative or 'resolver'"))
+                return Err(invalid_value("upstream.type", "must be 'authoritative' or 'resolver'"))
             }
         };
 
-        let upstream_servers = toml_config.lookup("upstream.servers")
-            .expect("upstream.servers is required")
-            .as_slice()
-            .expect("Invalid list of upstream servers")
-            .iter()
-            .map(|x| x.as_str().expect("upstream servers must be strings").to_owned())
-            .collect();
-
-        let failover_str = toml_config.lookup("upstream.strategy").map_or("uniform", |x| {
-            x.as_str().expect("upstream.strategy must be a string")
-        });
+        let upstream_servers = match try!(get_str_list(&toml_config, "upstream.servers")) {
+            None => return Err(ConfigError::MissingKey("upstream.servers")),
+            Some(upstream_servers) => upstream_servers,
+        };
+        if upstream_servers.is_empty() {
+            return Err(invalid_value("upstream.servers", "at least one server is required"));
+        }
+        let mut upstream_addrs: Vec<UpstreamAddr> = Vec::with_capacity(upstream_servers.len());
+        for upstream_server in upstream_servers {
+            match upstream_server.parse() {
+                Ok(parsed) => upstream_addrs.push(parsed),
+                Err(e) => {
+                    return Err(invalid_value("upstream.servers",
+                                             format!("{} ({})", upstream_server, e)))
+                }
+            }
+        }
+
+        let failover_str = try!(get_str(&toml_config, "upstream.strategy")).unwrap_or("uniform");
         let failover = match failover_str {
             "uniform" => false,
             "fallback" => true,
-            _ => {
-                return Err(Error::new(ErrorKind::InvalidData,
-                                      "Invalid value for the load balancing/failover strategy"))
-            }
+            _ => return Err(invalid_value("upstream.strategy", "must be 'uniform' or 'fallback'")),
         };
 
         let upstream_max_failures =
-            toml_config.lookup("upstream.max_failures").map_or(3, |x| {
-                x.as_integer().expect("upstream.max_failures must be an integer")
-            }) as u32;
+            try!(get_integer(&toml_config,
+                             "upstream.max_failures",
+                             3,
+                             0,
+                             u32::max_value() as i64)) as u32;
+
+        let upstream_initial_timeout_ms =
+            try!(get_integer(&toml_config, "upstream.initial_timeout_ms", 1_000, 1, 60_000)) as
+            u64;
+
+        let upstream_max_timeout_ms =
+            try!(get_integer(&toml_config, "upstream.max_timeout_ms", 8_000, 1, 60_000)) as u64;
+
+        if upstream_initial_timeout_ms > upstream_max_timeout_ms {
+            return Err(invalid_value("upstream.initial_timeout_ms",
+                                     "cannot be larger than upstream.max_timeout_ms"));
+        }
+
+        let health_check_ms =
+            try!(get_integer(&toml_config,
+                             "upstream.health_check_ms",
+                             10_000,
+                             100,
+                             3_600_000)) as u64;
+
+        let cache_size =
+            try!(get_integer(&toml_config, "cache.max_items", 250_000, 3, i64::max_value())) as
+            usize;
+
+        let min_ttl = try!(get_ttl(&toml_config, "cache.min_ttl", 60));
 
-        let cache_size = toml_config.lookup("cache.max_items").map_or(250_000, |x| {
-            x.as_integer().expect("cache.max_items must be an integer")
-        }) as usize;
+        let max_ttl = try!(get_ttl(&toml_config, "cache.max_ttl", 86_400));
 
-        let min_ttl = toml_config.lookup("cache.min_ttl").map_or(60, |x| {
-            x.as_integer().expect("cache.min_ttl must be an integer")
-        }) as u32;
+        if min_ttl > max_ttl {
+            return Err(invalid_value("cache.min_ttl", "cannot be larger than cache.max_ttl"));
+        }
 
-        let max_ttl = toml_config.lookup("cache.max_ttl").map_or(86_400, |x| {
-            x.as_integer().expect("cache.max_ttl must be an integer")
-        }) as u32;
+        let negative_min_ttl = try!(get_ttl(&toml_config, "cache.negative_min_ttl", 30));
 
-        let udp_ports = toml_config.lookup("network.udp_ports").map_or(8, |x| {
-            x.as_integer().expect("network.udp_ports must be an integer")
-        }) as u16;
+        let negative_max_ttl = try!(get_ttl(&toml_config, "cache.negative_max_ttl", 10_800));
 
-        let listen_addr = toml_config.lookup("network.listen")
-            .map_or("0.0.0.0:53",
-                    |x| x.as_str().expect("network.listen_addr must be a string"))
+        if negative_min_ttl > negative_max_ttl {
+            return Err(invalid_value("cache.negative_min_ttl",
+                                     "cannot be larger than cache.negative_max_ttl"));
+        }
+
+        let serve_stale_max = try!(get_ttl(&toml_config, "cache.serve_stale_max", 0));
+
+        let prefetch_threshold =
+            try!(get_integer(&toml_config, "cache.prefetch_threshold", 0, 0, 100)) as u32;
+
+        let prefetch_min_hits =
+            try!(get_integer(&toml_config,
+                             "cache.prefetch_min_hits",
+                             10,
+                             0,
+                             u32::max_value() as i64)) as u32;
+
+        let cache_snapshot_file = try!(get_str(&toml_config, "cache.snapshot_file"))
+            .map(|x| x.to_owned());
+
+        let cache_snapshot_interval =
+            try!(get_integer(&toml_config, "cache.snapshot_interval", 300, 0, i64::max_value())) as
+            u64;
+
+        let failure_ttl = try!(get_ttl(&toml_config, "cache.failure_ttl", 30));
+
+        let udp_ports = try!(get_integer(&toml_config, "network.udp_ports", 8, 1, 64511)) as u16;
+
+        let udp_listener_threads =
+            try!(get_integer(&toml_config, "network.udp_listener_threads", 1, 1, 1024)) as usize;
+
+        let listen_addrs = match toml_config.lookup("network.listen") {
+            None => vec!["0.0.0.0:53".to_owned()],
+            Some(&toml::Value::Array(_)) => {
+                try!(get_str_list(&toml_config, "network.listen")).unwrap_or_else(Vec::new)
+            }
+            Some(x) => {
+                vec![try!(x.as_str().ok_or(ConfigError::InvalidType {
+                             key: "network.listen",
+                             expected: "a string or a list of strings",
+                         }))
+                         .to_owned()]
+            }
+        };
+        if listen_addrs.is_empty() {
+            return Err(invalid_value("network.listen", "at least one address is required"));
+        }
+        for listen_addr in &listen_addrs {
+            try!(check_socket_addr("network.listen", listen_addr));
+        }
+
+        let udp_buffer_size =
+            try!(get_integer(&toml_config,
+                             "network.udp_buffer_size",
+                             16 * 1024 * 1024,
+                             4096,
+                             i32::max_value() as i64)) as usize;
+
+        let max_active_queries =
+            try!(get_integer(&toml_config,
+                             "network.max_active_queries",
+                             100_000,
+                             1,
+                             10_000_000)) as usize;
+
+        let max_tcp_clients =
+            try!(get_integer(&toml_config, "network.max_tcp_clients", 1_000, 1, 1_000_000)) as
+            usize;
+
+        let max_tcp_idle_ms =
+            try!(get_integer(&toml_config, "network.max_tcp_idle_ms", 10_000, 100, 3_600_000)) as
+            u64;
+
+        let webservice_enabled = try!(get_bool(&toml_config, "webservice.enabled", false));
+
+        let webservice_listen_addr = try!(get_str(&toml_config, "webservice.listen"))
+            .unwrap_or("0.0.0.0:9090")
             .to_owned();
+        try!(check_socket_addr("webservice.listen", &webservice_listen_addr));
 
-        let webservice_enabled = toml_config.lookup("webservice.enabled").map_or(false, |x| {
-            x.as_bool().expect("webservice.enabled must be a boolean")
-        });
+        let tls_enabled = try!(get_bool(&toml_config, "tls.enabled", false));
 
-        let webservice_listen_addr = toml_config.lookup("webservice.listen")
-            .map_or("0.0.0.0:9090",
-                    |x| x.as_str().expect("webservice.listen_addr must be a string"))
+        let tls_listen_addr = try!(get_str(&toml_config, "tls.listen"))
+            .unwrap_or("0.0.0.0:853")
             .to_owned();
+        try!(check_socket_addr("tls.listen", &tls_listen_addr));
+
+        let tls_cert_file = try!(get_str(&toml_config, "tls.cert_file")).map(|x| x.to_owned());
+
+        let tls_key_file = try!(get_str(&toml_config, "tls.key_file")).map(|x| x.to_owned());
+
+        if tls_enabled {
+            if tls_cert_file.is_none() {
+                return Err(ConfigError::MissingKey("tls.cert_file"));
+            }
+            if tls_key_file.is_none() {
+                return Err(ConfigError::MissingKey("tls.key_file"));
+            }
+        }
+
+        let rrl_responses_per_second =
+            try!(get_integer(&toml_config,
+                             "rrl.responses_per_second",
+                             0,
+                             0,
+                             u32::max_value() as i64)) as u32;
+
+        let rrl_slip =
+            try!(get_integer(&toml_config, "rrl.slip", 2, 0, u32::max_value() as i64)) as u32;
+
+        let rrl_log_only = try!(get_bool(&toml_config, "rrl.log_only", false));
+
+        let rrl_ipv4_prefix_len =
+            try!(get_integer(&toml_config, "rrl.ipv4_prefix_len", 24, 0, 32)) as u8;
 
-        let user = toml_config.lookup("global.user")
-            .map(|x| x.as_str().expect("global.user must be a string").to_owned());
+        let rrl_ipv6_prefix_len =
+            try!(get_integer(&toml_config, "rrl.ipv6_prefix_len", 56, 0, 128)) as u8;
 
-        let group = toml_config.lookup("global.group")
-            .map(|x| x.as_str().expect("global.group must be a string").to_owned());
+        let acl_allow = try!(Self::parse_cidrs(&toml_config, "acl.allow"));
 
-        let chroot_dir = toml_config.lookup("global.chroot_dir")
-            .map(|x| x.as_str().expect("global.chroot must be a string").to_owned());
+        let acl_deny = try!(Self::parse_cidrs(&toml_config, "acl.deny"));
+
+        let acl_action_str = try!(get_str(&toml_config, "acl.action")).unwrap_or("refuse");
+        let acl_action = match acl_action_str {
+            "refuse" => AclAction::Refuse,
+            "drop" => AclAction::Drop,
+            _ => return Err(invalid_value("acl.action", "must be 'refuse' or 'drop'")),
+        };
+
+        let client_max_queries_per_second =
+            try!(get_integer(&toml_config,
+                             "client_limits.queries_per_second",
+                             0,
+                             0,
+                             u32::max_value() as i64)) as u32;
+
+        let client_max_tcp_connections =
+            try!(get_integer(&toml_config,
+                             "client_limits.tcp_connections",
+                             0,
+                             0,
+                             i64::max_value())) as usize;
+
+        let client_ipv4_prefix_len =
+            try!(get_integer(&toml_config, "client_limits.ipv4_prefix_len", 32, 0, 32)) as u8;
+
+        let client_ipv6_prefix_len =
+            try!(get_integer(&toml_config, "client_limits.ipv6_prefix_len", 64, 0, 128)) as u8;
+
+        let heavy_hitters_enabled = try!(get_bool(&toml_config, "heavy_hitters.enabled", false));
+
+        let heavy_hitters_top =
+            try!(get_integer(&toml_config, "heavy_hitters.top", 20, 1, 1_000)) as usize;
+
+        let heavy_hitters_capacity =
+            try!(get_integer(&toml_config, "heavy_hitters.capacity", 1_000, 1, 100_000)) as usize;
+        if heavy_hitters_capacity < heavy_hitters_top {
+            return Err(invalid_value("heavy_hitters.capacity",
+                                     "must be greater than or equal to heavy_hitters.top"));
+        }
+
+        let heavy_hitters_decay_window =
+            try!(get_integer(&toml_config, "heavy_hitters.decay_window", 60, 1, 86_400)) as u64;
+
+        let query_log_enabled = try!(get_bool(&toml_config, "query_log.enabled", false));
+
+        let query_log_file = try!(get_str(&toml_config, "query_log.file")).map(|x| x.to_owned());
+
+        let query_log_socket =
+            try!(get_str(&toml_config, "query_log.socket")).map(|x| x.to_owned());
+        if query_log_enabled {
+            if query_log_file.is_some() && query_log_socket.is_some() {
+                return Err(invalid_value("query_log.socket",
+                                         "cannot be used together with query_log.file"));
+            }
+            if query_log_file.is_none() && query_log_socket.is_none() {
+                return Err(ConfigError::MissingKey("query_log.file"));
+            }
+        }
+
+        let query_log_max_file_size =
+            try!(get_integer(&toml_config,
+                             "query_log.max_file_size",
+                             100 * 1024 * 1024,
+                             0,
+                             i64::max_value())) as u64;
+
+        let query_log_max_files =
+            try!(get_integer(&toml_config, "query_log.max_files", 5, 1, 1_000)) as usize;
+
+        let query_log_sample_rate =
+            try!(get_integer(&toml_config, "query_log.sample_rate", 1, 1, 1_000_000)) as u32;
+
+        let user = try!(get_str(&toml_config, "global.user")).map(|x| x.to_owned());
+
+        let group = try!(get_str(&toml_config, "global.group")).map(|x| x.to_owned());
+
+        let chroot_dir = try!(get_str(&toml_config, "global.chroot_dir")).map(|x| x.to_owned());
+
+        let shutdown_timeout =
+            try!(get_integer(&toml_config, "global.shutdown_timeout", 5, 0, i64::max_value())) as
+            u64;
 
         Ok(Config {
             decrement_ttl: decrement_ttl,
-            upstream_servers: upstream_servers,
+            upstream_servers: upstream_addrs,
             failover: failover,
             upstream_max_failures: upstream_max_failures,
+            upstream_initial_timeout_ms: upstream_initial_timeout_ms,
+            upstream_max_timeout_ms: upstream_max_timeout_ms,
+            health_check_ms: health_check_ms,
             cache_size: cache_size,
             udp_ports: udp_ports,
-            listen_addr: listen_addr,
+            udp_listener_threads: udp_listener_threads,
+            listen_addrs: listen_addrs,
+            udp_buffer_size: udp_buffer_size,
+            max_active_queries: max_active_queries,
+            max_tcp_clients: max_tcp_clients,
+            max_tcp_idle_ms: max_tcp_idle_ms,
             webservice_enabled: webservice_enabled,
             webservice_listen_addr: webservice_listen_addr,
+            tls_enabled: tls_enabled,
+            tls_listen_addr: tls_listen_addr,
+            tls_cert_file: tls_cert_file,
+            tls_key_file: tls_key_file,
             min_ttl: min_ttl,
             max_ttl: max_ttl,
+            negative_min_ttl: negative_min_ttl,
+            negative_max_ttl: negative_max_ttl,
+            serve_stale_max: serve_stale_max,
+            prefetch_threshold: prefetch_threshold,
+            prefetch_min_hits: prefetch_min_hits,
+            cache_snapshot_file: cache_snapshot_file,
+            cache_snapshot_interval: cache_snapshot_interval,
+            failure_ttl: failure_ttl,
+            rrl_responses_per_second: rrl_responses_per_second,
+            rrl_slip: rrl_slip,
+            rrl_log_only: rrl_log_only,
+            rrl_ipv4_prefix_len: rrl_ipv4_prefix_len,
+            rrl_ipv6_prefix_len: rrl_ipv6_prefix_len,
+            acl_allow: acl_allow,
+            acl_deny: acl_deny,
+            acl_action: acl_action,
+            client_max_queries_per_second: client_max_queries_per_second,
+            client_max_tcp_connections: client_max_tcp_connections,
+            client_ipv4_prefix_len: client_ipv4_prefix_len,
+            client_ipv6_prefix_len: client_ipv6_prefix_len,
+            heavy_hitters_enabled: heavy_hitters_enabled,
+            heavy_hitters_top: heavy_hitters_top,
+            heavy_hitters_capacity: heavy_hitters_capacity,
+            heavy_hitters_decay_window: heavy_hitters_decay_window,
+            query_log_enabled: query_log_enabled,
+            query_log_file: query_log_file,
+            query_log_socket: query_log_socket,
+            query_log_max_file_size: query_log_max_file_size,
+            query_log_max_files: query_log_max_files,
+            query_log_sample_rate: query_log_sample_rate,
             user: user,
             group: group,
             chroot_dir: chroot_dir,
+            shutdown_timeout: shutdown_timeout,
         })
     }
+
+    pub fn restart_required(&self, new_config: &Config) -> Vec<&'static str> {
+        let changes = [("cache.max_items", self.cache_size != new_config.cache_size),
+                       ("cache.snapshot_file",
+                        self.cache_snapshot_file != new_config.cache_snapshot_file),
+                       ("network.udp_ports", self.udp_ports != new_config.udp_ports),
+                       ("network.udp_listener_threads",
+                        self.udp_listener_threads != new_config.udp_listener_threads),
+                       ("network.listen", self.listen_addrs != new_config.listen_addrs),
+                       ("network.udp_buffer_size",
+                        self.udp_buffer_size != new_config.udp_buffer_size),
+                       ("network.max_active_queries",
+                        self.max_active_queries != new_config.max_active_queries),
+                       ("network.max_tcp_clients",
+                        self.max_tcp_clients != new_config.max_tcp_clients),
+                       ("network.max_tcp_idle_ms",
+                        self.max_tcp_idle_ms != new_config.max_tcp_idle_ms),
+                       ("webservice",
+                        self.webservice_enabled != new_config.webservice_enabled ||
+                        self.webservice_listen_addr != new_config.webservice_listen_addr),
+                       ("tls",
+                        self.tls_enabled != new_config.tls_enabled ||
+                        self.tls_listen_addr != new_config.tls_listen_addr ||
+                        self.tls_cert_file != new_config.tls_cert_file ||
+                        self.tls_key_file != new_config.tls_key_file),
+                       ("rrl",
+                        self.rrl_responses_per_second != new_config.rrl_responses_per_second ||
+                        self.rrl_slip != new_config.rrl_slip ||
+                        self.rrl_log_only != new_config.rrl_log_only ||
+                        self.rrl_ipv4_prefix_len != new_config.rrl_ipv4_prefix_len ||
+                        self.rrl_ipv6_prefix_len != new_config.rrl_ipv6_prefix_len),
+                       ("acl",
+                        self.acl_allow != new_config.acl_allow ||
+                        self.acl_deny != new_config.acl_deny ||
+                        self.acl_action != new_config.acl_action),
+                       ("client_limits",
+                        self.client_max_queries_per_second !=
+                        new_config.client_max_queries_per_second ||
+                        self.client_max_tcp_connections != new_config.client_max_tcp_connections ||
+                        self.client_ipv4_prefix_len != new_config.client_ipv4_prefix_len ||
+                        self.client_ipv6_prefix_len != new_config.client_ipv6_prefix_len),
+                       ("heavy_hitters",
+                        self.heavy_hitters_enabled != new_config.heavy_hitters_enabled ||
+                        self.heavy_hitters_top != new_config.heavy_hitters_top ||
+                        self.heavy_hitters_capacity != new_config.heavy_hitters_capacity ||
+                        self.heavy_hitters_decay_window != new_config.heavy_hitters_decay_window),
+                       ("query_log",
+                        self.query_log_enabled != new_config.query_log_enabled ||
+                        self.query_log_file != new_config.query_log_file ||
+                        self.query_log_socket != new_config.query_log_socket ||
+                        self.query_log_max_file_size != new_config.query_log_max_file_size ||
+                        self.query_log_max_files != new_config.query_log_max_files ||
+                        self.query_log_sample_rate != new_config.query_log_sample_rate),
+                       ("global",
+                        self.user != new_config.user || self.group != new_config.group ||
+                        self.chroot_dir != new_config.chroot_dir ||
+                        self.shutdown_timeout != new_config.shutdown_timeout)];
+        changes.iter().filter(|&&(_, changed)| changed).map(|&(setting, _)| setting).collect()
+    }
+
+    fn parse_cidrs(toml_config: &toml::Value,
+                   key: &'static str)
+                   -> Result<Vec<Cidr>, ConfigError> {
+        let cidrs = match try!(get_str_list(toml_config, key)) {
+            None => return Ok(vec![]),
+            Some(cidrs) => cidrs,
+        };
+        let mut res = Vec::with_capacity(cidrs.len());
+        for cidr in cidrs {
+            match cidr.parse() {
+                Ok(parsed) => res.push(parsed),
+                Err(e) => return Err(invalid_value(key, format!("{} ({})", cidr, e))),
+            }
+        }
+        Ok(res)
+    }
+}
+
+fn check_unknown_keys(toml_config: &toml::Value) -> Result<(), ConfigError> {
+    let sections = match *toml_config {
+        toml::Value::Table(ref sections) => sections,
+        _ => return Ok(()),
+    };
+    for (section, keys) in sections {
+        let known_keys = match KNOWN_KEYS.iter().find(|&&(name, _)| name == *section) {
+            None => return Err(ConfigError::UnknownKey(section.clone())),
+            Some(&(_, known_keys)) => known_keys,
+        };
+        let keys = match *keys {
+            toml::Value::Table(ref keys) => keys,
+            _ => return Err(ConfigError::UnknownKey(section.clone())),
+        };
+        for key in keys.keys() {
+            if !known_keys.contains(&key.as_str()) {
+                return Err(ConfigError::UnknownKey(format!("{}.{}", section, key)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn invalid_value<T: Into<String>>(key: &'static str, reason: T) -> ConfigError {
+    ConfigError::InvalidValue {
+        key: key,
+        reason: reason.into(),
+    }
+}
+
+fn get_integer(toml_config: &toml::Value,
+               key: &'static str,
+               default: i64,
+               min: i64,
+               max: i64)
+               -> Result<i64, ConfigError> {
+    let value = match toml_config.lookup(key) {
+        None => return Ok(default),
+        Some(x) => {
+            try!(x.as_integer().ok_or(ConfigError::InvalidType {
+                key: key,
+                expected: "an integer",
+            }))
+        }
+    };
+    if value < min || value > max {
+        return Err(invalid_value(key, format!("must be between {} and {}", min, max)));
+    }
+    Ok(value)
+}
+
+fn get_ttl(toml_config: &toml::Value, key: &'static str, default: u32) -> Result<u32, ConfigError> {
+    get_integer(toml_config, key, default as i64, 0, u32::max_value() as i64).map(|x| x as u32)
+}
+
+fn get_bool(toml_config: &toml::Value,
+            key: &'static str,
+            default: bool)
+            -> Result<bool, ConfigError> {
+    match toml_config.lookup(key) {
+        None => Ok(default),
+        Some(x) => {
+            x.as_bool().ok_or(ConfigError::InvalidType {
+                key: key,
+                expected: "a boolean",
+            })
+        }
+    }
+}
+
+fn get_str<'t>(toml_config: &'t toml::Value,
+               key: &'static str)
+               -> Result<Option<&'t str>, ConfigError> {
+    match toml_config.lookup(key) {
+        None => Ok(None),
+        Some(x) => {
+            x.as_str()
+                .map(Some)
+                .ok_or(ConfigError::InvalidType {
+                    key: key,
+                    expected: "a string",
+                })
+        }
+    }
+}
+
+fn get_str_list(toml_config: &toml::Value,
+                key: &'static str)
+                -> Result<Option<Vec<String>>, ConfigError> {
+    let values = match toml_config.lookup(key).map(|x| x.as_slice()) {
+        None => return Ok(None),
+        Some(values) => values,
+    };
+    let strs: Option<Vec<String>> = values.and_then(|values| {
+        values.iter().map(|x| x.as_str().map(|x| x.to_owned())).collect()
+    });
+    match strs {
+        None => {
+            Err(ConfigError::InvalidType {
+                key: key,
+                expected: "a list of strings",
+            })
+        }
+        Some(strs) => Ok(Some(strs)),
+    }
+}
+
+fn check_socket_addr(key: &'static str, addr: &str) -> Result<(), ConfigError> {
+    match addr.parse::<SocketAddr>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(invalid_value(key, format!("{} is not a valid IP:port address", addr))),
+    }
 }
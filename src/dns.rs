@@ -1,4 +1,5 @@
 use rand::random;
+use std::cmp;
 use std::fmt;
 use std::io::Write;
 
@@ -14,10 +15,14 @@ pub const DNS_OFFSET_EDNS_PAYLOAD_SIZE: usize = 2;
 pub const DNS_OFFSET_EDNS_TYPE: usize = 0;
 pub const DNS_OFFSET_QUESTION: usize = DNS_HEADER_SIZE;
 pub const DNS_QTYPE_PLUS_QCLASS_LEN: usize = 4;
+pub const DNS_RCODE_NOERROR: u8 = 0;
 pub const DNS_RCODE_SERVFAIL: u8 = 2;
 pub const DNS_RCODE_NXDOMAIN: u8 = 3;
 pub const DNS_RCODE_REFUSED: u8 = 5;
+pub const DNS_TYPE_A: u16 = 1;
+pub const DNS_TYPE_AAAA: u16 = 28;
 pub const DNS_TYPE_ANY: u16 = 255;
+pub const DNS_TYPE_MX: u16 = 15;
 pub const DNS_TYPE_OPT: u16 = 41;
 pub const DNS_TYPE_HINFO: u16 = 13;
 pub const DNS_TYPE_SOA: u16 = 6;
@@ -340,24 +345,7 @@ fn parse_edns0(packet: &[u8]) -> Option<EDNS0> {
 
 impl fmt::Display for NormalizedQuestion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let qname = &self.qname;
-        let qname_len = qname.len();
-        let mut res = Vec::with_capacity(qname_len);
-        let mut offset: usize = 0;
-        while offset < qname_len {
-            let label_len = qname[offset] as usize;
-            assert!(label_len != 0);
-            if label_len & 0xc0 == 0xc0 {
-                res.push(b'&');
-                offset += 2;
-                continue;
-            }
-            offset += 1;
-            res.extend_from_slice(&qname[offset..offset + label_len]);
-            res.push(b'.');
-            offset += label_len;
-        }
-        let qname_str = String::from_utf8_lossy(&res);
+        let qname_str = qname_to_string(&self.qname);
         write!(f, "[{}]\t{} {}", qname_str, self.qtype, self.qclass)
     }
 }
@@ -387,6 +375,26 @@ impl NormalizedQuestion {
     }
 }
 
+pub fn qname_to_string(qname: &[u8]) -> String {
+    let qname_len = qname.len();
+    let mut res = Vec::with_capacity(qname_len);
+    let mut offset: usize = 0;
+    while offset < qname_len {
+        let label_len = qname[offset] as usize;
+        assert!(label_len != 0);
+        if label_len & 0xc0 == 0xc0 {
+            res.push(b'&');
+            offset += 2;
+            continue;
+        }
+        offset += 1;
+        res.extend_from_slice(&qname[offset..offset + label_len]);
+        res.push(b'.');
+        offset += label_len;
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
 pub fn qname_lc(qname: &[u8]) -> Vec<u8> {
     let qname_len = qname.len();
     let mut res = vec![0u8; qname_len];
@@ -467,9 +475,104 @@ pub fn normalize(packet: &[u8], is_question: bool) -> Result<NormalizedQuestion,
     Ok(normalized_question)
 }
 
+pub fn is_negative(packet: &[u8]) -> bool {
+    let rcode = rcode(packet);
+    rcode == DNS_RCODE_NXDOMAIN || (rcode == DNS_RCODE_NOERROR && ancount(packet) == 0)
+}
+
+fn read_name(packet: &[u8], offset: usize) -> Result<Vec<u8>, &'static str> {
+    let packet_len = packet.len();
+    let mut name = Vec::new();
+    let mut offset = offset;
+    let mut pointers = 0;
+    loop {
+        if offset >= packet_len {
+            return Err("Short packet");
+        }
+        let label_len = packet[offset] as usize;
+        if label_len & 0xc0 == 0xc0 {
+            if 2 > packet_len - offset {
+                return Err("Incomplete offset");
+            }
+            pointers += 1;
+            if pointers > DNS_MAX_HOSTNAME_LEN / 2 {
+                return Err("Too many compression pointers");
+            }
+            offset = (label_len & 0x3f) << 8 | packet[offset + 1] as usize;
+            continue;
+        }
+        if label_len == 0 {
+            break;
+        }
+        if label_len & 0xc0 != 0 {
+            return Err("Unsupported label type");
+        }
+        if label_len >= packet_len - offset {
+            return Err("Malformed packet with an out-of-bounds name");
+        }
+        if name.len() + label_len + 1 > DNS_MAX_HOSTNAME_LEN {
+            return Err("Name too long");
+        }
+        name.extend_from_slice(&packet[offset..offset + 1 + label_len]);
+        offset += label_len + 1;
+    }
+    Ok(name)
+}
+
+pub fn soa_owner(packet: &[u8]) -> Option<Vec<u8>> {
+    let packet_len = packet.len();
+    if qdcount(packet) != 1 || packet_len <= DNS_OFFSET_QUESTION {
+        return None;
+    }
+    let mut offset = match skip_name(packet, DNS_OFFSET_QUESTION) {
+        Ok(offset) => offset.0 + DNS_QTYPE_PLUS_QCLASS_LEN,
+        Err(_) => return None,
+    };
+    let ancount = ancount(packet) as usize;
+    let nscount = nscount(packet) as usize;
+    for i in 0..(ancount + nscount) {
+        if offset >= packet_len {
+            return None;
+        }
+        let owner_offset = offset;
+        offset = match skip_name(packet, offset) {
+            Ok(offset) => offset.0,
+            Err(_) => return None,
+        };
+        if 10 > packet_len - offset {
+            return None;
+        }
+        let rr_type = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
+        if i >= ancount && rr_type == DNS_TYPE_SOA {
+            return read_name(packet, owner_offset).ok();
+        }
+        let rdlen = ((packet[offset + 8] as u16) << 8 | packet[offset + 9] as u16) as usize;
+        offset += 10;
+        if rdlen > packet_len - offset {
+            return None;
+        }
+        offset += rdlen;
+    }
+    None
+}
+
+fn soa_minimum(packet: &[u8], offset: usize, rdlen: usize) -> Result<u32, &'static str> {
+    let rdata_end = offset + rdlen;
+    let offset = try!(skip_name(packet, offset)).0;
+    let offset = try!(skip_name(packet, offset)).0;
+    if offset + 20 != rdata_end {
+        return Err("Invalid SOA record");
+    }
+    let offset = offset + 16;
+    Ok((packet[offset] as u32) << 24 | (packet[offset + 1] as u32) << 16 |
+       (packet[offset + 2] as u32) << 8 | packet[offset + 3] as u32)
+}
+
 pub fn min_ttl(packet: &[u8],
                min_ttl: u32,
                max_ttl: u32,
+               negative_min_ttl: u32,
+               negative_max_ttl: u32,
                failure_ttl: u32)
                -> Result<u32, &'static str> {
     if qdcount(packet) != 1 {
@@ -496,8 +599,10 @@ pub fn min_ttl(packet: &[u8],
     let nscount = nscount(packet);
     let arcount = arcount(packet);
     let rrcount = ancount + nscount + arcount;
+    let negative = is_negative(packet);
     let mut found_min_ttl = if rrcount > 0 { max_ttl } else { failure_ttl };
-    for _ in 0..rrcount {
+    let mut found_negative_ttl: Option<u32> = None;
+    for i in 0..rrcount {
         offset = match skip_name(packet, offset) {
             Ok(offset) => offset.0,
             Err(e) => return Err(e),
@@ -522,18 +627,53 @@ pub fn min_ttl(packet: &[u8],
         if rdlen > packet_len - offset {
             return Err("Record length would exceed packet length");
         }
+        if negative && qtype == DNS_TYPE_SOA && i >= ancount && i < ancount + nscount {
+            let negative_ttl = cmp::min(ttl, try!(soa_minimum(packet, offset, rdlen)));
+            found_negative_ttl =
+                Some(found_negative_ttl.map_or(negative_ttl, |x| cmp::min(x, negative_ttl)));
+        }
         offset += rdlen;
     }
-    if found_min_ttl < min_ttl {
-        found_min_ttl = min_ttl;
-    }
     if offset != packet_len {
         return Err("Garbage after packet");
     }
+    let negative_ttl = match found_negative_ttl {
+        Some(negative_ttl) => Some(negative_ttl),
+        None if rcode(packet) == DNS_RCODE_NXDOMAIN => Some(failure_ttl),
+        None => None,
+    };
+    if let Some(negative_ttl) = negative_ttl {
+        return Ok(cmp::max(negative_min_ttl, cmp::min(negative_ttl, negative_max_ttl)));
+    }
+    if found_min_ttl < min_ttl {
+        found_min_ttl = min_ttl;
+    }
     Ok(found_min_ttl)
 }
 
 pub fn set_ttl(packet: &mut [u8], ttl: u32) -> Result<(), &'static str> {
+    update_ttls(packet, |_| ttl)
+}
+
+pub fn clamp_ttls(packet: &mut [u8], min_ttl: u32, max_ttl: u32) -> Result<(), &'static str> {
+    update_ttls(packet, |ttl| {
+        if ttl < min_ttl {
+            min_ttl
+        } else if ttl > max_ttl {
+            max_ttl
+        } else {
+            ttl
+        }
+    })
+}
+
+pub fn decrement_ttls(packet: &mut [u8], elapsed: u32) -> Result<(), &'static str> {
+    update_ttls(packet, |ttl| ttl.saturating_sub(elapsed))
+}
+
+fn update_ttls<F>(packet: &mut [u8], f: F) -> Result<(), &'static str>
+    where F: Fn(u32) -> u32
+{
     if qdcount(packet) != 1 {
         return Err("Unsupported number of questions");
     }
@@ -566,8 +706,10 @@ pub fn set_ttl(packet: &mut [u8], ttl: u32) -> Result<(), &'static str> {
             return Err("Short packet");
         }
         let qtype = (packet[offset] as u16) << 8 | packet[offset + 1] as u16;
-        let qclass = (packet[offset + 2] as u16) << 8 | packet[offset + 3] as u16;
-        if qtype != DNS_TYPE_OPT || qclass != DNS_CLASS_IN {
+        if qtype != DNS_TYPE_OPT {
+            let ttl = f((packet[offset + 4] as u32) << 24 | (packet[offset + 5] as u32) << 16 |
+                        (packet[offset + 6] as u32) << 8 |
+                        packet[offset + 7] as u32);
             packet[offset + 4] = (ttl >> 24) as u8;
             packet[offset + 5] = (ttl >> 16) as u8;
             packet[offset + 6] = (ttl >> 8) as u8;
@@ -824,3 +966,162 @@ pub fn build_query_packet(normalized_question: &NormalizedQuestion,
     };
     Ok((packet, normalized_question_minimal))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &'static [u8] = b"\x07example\x03com\x00";
+
+    fn push_u16(packet: &mut Vec<u8>, value: u16) {
+        packet.push((value >> 8) as u8);
+        packet.push(value as u8);
+    }
+
+    fn push_u32(packet: &mut Vec<u8>, value: u32) {
+        push_u16(packet, (value >> 16) as u16);
+        push_u16(packet, value as u16);
+    }
+
+    fn soa_rdata(minimum: u32) -> Vec<u8> {
+        let mut rdata = Vec::new();
+        rdata.extend_from_slice(b"\x02ns\x07example\x03com\x00");
+        rdata.extend_from_slice(b"\x04root\x07example\x03com\x00");
+        for value in &[1u32, 7200, 3600, 1209600] {
+            push_u32(&mut rdata, *value);
+        }
+        push_u32(&mut rdata, minimum);
+        rdata
+    }
+
+    fn response(rcode: u8,
+                answers: &[(u16, u32, Vec<u8>)],
+                authority: &[(u16, u32, Vec<u8>)])
+                -> Vec<u8> {
+        let mut packet = vec![0u8; DNS_HEADER_SIZE];
+        set_qr(&mut packet, true);
+        set_rcode(&mut packet, rcode);
+        set_qdcount(&mut packet, 1);
+        set_ancount(&mut packet, answers.len() as u16);
+        set_nscount(&mut packet, authority.len() as u16);
+        packet.extend_from_slice(NAME);
+        push_u16(&mut packet, DNS_TYPE_A);
+        push_u16(&mut packet, DNS_CLASS_IN);
+        for &(rr_type, ttl, ref rdata) in answers.iter().chain(authority.iter()) {
+            packet.extend_from_slice(NAME);
+            push_u16(&mut packet, rr_type);
+            push_u16(&mut packet, DNS_CLASS_IN);
+            push_u32(&mut packet, ttl);
+            push_u16(&mut packet, rdata.len() as u16);
+            packet.extend_from_slice(rdata);
+        }
+        packet
+    }
+
+    fn ttl(packet: &[u8]) -> Result<u32, &'static str> {
+        min_ttl(packet, 10, 86400, 5, 3600, 2)
+    }
+
+    #[test]
+    fn test_min_ttl_positive() {
+        let packet = response(DNS_RCODE_NOERROR,
+                              &[(DNS_TYPE_A, 300, vec![192, 0, 2, 1]),
+                                (DNS_TYPE_A, 120, vec![192, 0, 2, 2])],
+                              &[]);
+        assert_eq!(ttl(&packet), Ok(120));
+        let packet = response(DNS_RCODE_NOERROR, &[(DNS_TYPE_A, 1, vec![192, 0, 2, 1])], &[]);
+        assert_eq!(ttl(&packet), Ok(10));
+        let packet = response(DNS_RCODE_NOERROR,
+                              &[(DNS_TYPE_A, 100_000, vec![192, 0, 2, 1])],
+                              &[]);
+        assert_eq!(ttl(&packet), Ok(86400));
+    }
+
+    #[test]
+    fn test_min_ttl_negative_uses_soa_minimum() {
+        let packet = response(DNS_RCODE_NXDOMAIN, &[], &[(DNS_TYPE_SOA, 900, soa_rdata(60))]);
+        assert_eq!(ttl(&packet), Ok(60));
+        let packet = response(DNS_RCODE_NXDOMAIN, &[], &[(DNS_TYPE_SOA, 30, soa_rdata(600))]);
+        assert_eq!(ttl(&packet), Ok(30));
+        let packet = response(DNS_RCODE_NOERROR, &[], &[(DNS_TYPE_SOA, 900, soa_rdata(60))]);
+        assert_eq!(ttl(&packet), Ok(60));
+    }
+
+    #[test]
+    fn test_min_ttl_negative_bounds() {
+        let packet = response(DNS_RCODE_NXDOMAIN, &[], &[(DNS_TYPE_SOA, 900, soa_rdata(0))]);
+        assert_eq!(ttl(&packet), Ok(5));
+        let packet = response(DNS_RCODE_NXDOMAIN,
+                              &[],
+                              &[(DNS_TYPE_SOA, 86400, soa_rdata(86400))]);
+        assert_eq!(ttl(&packet), Ok(3600));
+    }
+
+    #[test]
+    fn test_min_ttl_nxdomain_without_soa() {
+        let packet = response(DNS_RCODE_NXDOMAIN, &[], &[]);
+        assert_eq!(ttl(&packet), Ok(5));
+        assert_eq!(min_ttl(&packet, 10, 86400, 0, 3600, 2), Ok(2));
+    }
+
+    #[test]
+    fn test_min_ttl_soa_in_answer_section_is_not_negative() {
+        let packet = response(DNS_RCODE_NOERROR, &[(DNS_TYPE_SOA, 900, soa_rdata(60))], &[]);
+        assert_eq!(ttl(&packet), Ok(900));
+    }
+
+    #[test]
+    fn test_min_ttl_invalid_soa() {
+        let mut rdata = soa_rdata(60);
+        rdata.push(0);
+        let packet = response(DNS_RCODE_NXDOMAIN, &[], &[(DNS_TYPE_SOA, 900, rdata)]);
+        assert!(ttl(&packet).is_err());
+        let mut rdata = soa_rdata(60);
+        rdata.pop();
+        let packet = response(DNS_RCODE_NXDOMAIN, &[], &[(DNS_TYPE_SOA, 900, rdata)]);
+        assert!(ttl(&packet).is_err());
+    }
+
+    #[test]
+    fn test_min_ttl_truncated_record() {
+        let mut packet = response(DNS_RCODE_NOERROR, &[(DNS_TYPE_A, 300, vec![192, 0, 2, 1])], &[]);
+        packet.pop();
+        assert!(ttl(&packet).is_err());
+        let mut packet = response(DNS_RCODE_NOERROR, &[(DNS_TYPE_A, 300, vec![192, 0, 2, 1])], &[]);
+        packet.push(0);
+        assert!(ttl(&packet).is_err());
+    }
+
+    #[test]
+    fn test_update_ttls_skips_opt() {
+        let mut packet = response(DNS_RCODE_NOERROR, &[(DNS_TYPE_A, 300, vec![192, 0, 2, 1])], &[]);
+        let a_ttl_offset = packet.len() - 10;
+        set_arcount(&mut packet, 1);
+        packet.push(0);
+        push_u16(&mut packet, DNS_TYPE_OPT);
+        push_u16(&mut packet, 4096);
+        push_u32(&mut packet, 0x0000_8000);
+        push_u16(&mut packet, 0);
+        let opt_ttl_offset = packet.len() - 6;
+        let opt_ttl = packet[opt_ttl_offset..opt_ttl_offset + 4].to_vec();
+
+        assert_eq!(decrement_ttls(&mut packet, 100), Ok(()));
+        assert_eq!(&packet[a_ttl_offset..a_ttl_offset + 4], &[0, 0, 0, 200]);
+        assert_eq!(clamp_ttls(&mut packet, 250, 3600), Ok(()));
+        assert_eq!(&packet[a_ttl_offset..a_ttl_offset + 4], &[0, 0, 0, 250]);
+        assert_eq!(set_ttl(&mut packet, 30), Ok(()));
+        assert_eq!(&packet[a_ttl_offset..a_ttl_offset + 4], &[0, 0, 0, 30]);
+        assert_eq!(&packet[opt_ttl_offset..opt_ttl_offset + 4], &opt_ttl[..]);
+    }
+
+    #[test]
+    fn test_soa_minimum() {
+        let rdata = soa_rdata(0xdeadbeef);
+        let mut packet = vec![0u8; 3];
+        packet.extend_from_slice(&rdata);
+        assert_eq!(soa_minimum(&packet, 3, rdata.len()), Ok(0xdeadbeef));
+        assert!(soa_minimum(&packet, 3, rdata.len() - 1).is_err());
+        assert!(soa_minimum(&packet, 3, rdata.len() + 1).is_err());
+        assert!(soa_minimum(&packet[..packet.len() - 21], 3, rdata.len()).is_err());
+    }
+}
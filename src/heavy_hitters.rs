@@ -0,0 +1,381 @@
+use acl::client_prefix;
+use config::Config;
+use dns;
+use json::json_string;
+use siphasher::sip::SipHasher13;
+use std::borrow::Borrow;
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{HEAVY_HITTERS_IPV4_PREFIX_LEN, HEAVY_HITTERS_IPV6_PREFIX_LEN, HEAVY_HITTERS_SHARDS};
+
+struct Slot<K> {
+    key: K,
+    count: u64,
+    error: u64,
+}
+
+// Space-Saving: the least frequent key is evicted when the table is full,
+// and its count is inherited by the new key, as an upper bound of the
+// error. Slots are kept in a min-heap ordered by count.
+struct SpaceSaving<K: Hash + Eq + Clone> {
+    capacity: usize,
+    heap: Vec<Slot<K>>,
+    positions: HashMap<K, usize>,
+}
+
+impl<K: Hash + Eq + Clone> SpaceSaving<K> {
+    fn new(capacity: usize) -> SpaceSaving<K> {
+        SpaceSaving {
+            capacity: capacity,
+            heap: Vec::with_capacity(capacity),
+            positions: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn insert<Q: ?Sized>(&mut self, key: &Q)
+        where K: Borrow<Q>,
+              Q: Hash + Eq + ToOwned<Owned = K>
+    {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(&idx) = self.positions.get(key) {
+            self.heap[idx].count += 1;
+            self.sift_down(idx);
+            return;
+        }
+        let key = key.to_owned();
+        if self.heap.len() < self.capacity {
+            let idx = self.heap.len();
+            self.positions.insert(key.clone(), idx);
+            self.heap.push(Slot {
+                key: key,
+                count: 1,
+                error: 0,
+            });
+            self.sift_up(idx);
+            return;
+        }
+        let min_count = self.heap[0].count;
+        let slot = Slot {
+            key: key.clone(),
+            count: min_count + 1,
+            error: min_count,
+        };
+        let evicted = mem::replace(&mut self.heap[0], slot);
+        self.positions.remove(&evicted.key);
+        self.positions.insert(key, 0);
+        self.sift_down(0);
+    }
+
+    fn decay(&mut self, shift: u32) {
+        for slot in &mut self.heap {
+            slot.count = slot.count.checked_shr(shift).unwrap_or(0);
+            slot.error = slot.error.checked_shr(shift).unwrap_or(0);
+        }
+    }
+
+    fn top(&self, n: usize) -> Vec<&Slot<K>> {
+        let mut slots: Vec<&Slot<K>> = self.heap.iter().filter(|slot| slot.count > 0).collect();
+        slots.sort_by(|a, b| b.count.cmp(&a.count));
+        slots.truncate(n);
+        slots
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        *self.positions.get_mut(&self.heap[a].key).unwrap() = a;
+        *self.positions.get_mut(&self.heap[b].key).unwrap() = b;
+    }
+
+    fn sift_up(&mut self, mut idx: usize) {
+        while idx > 0 {
+            let parent = (idx - 1) / 2;
+            if self.heap[parent].count <= self.heap[idx].count {
+                break;
+            }
+            self.swap(idx, parent);
+            idx = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut idx: usize) {
+        let len = self.heap.len();
+        loop {
+            let left = 2 * idx + 1;
+            let right = left + 1;
+            let mut smallest = idx;
+            if left < len && self.heap[left].count < self.heap[smallest].count {
+                smallest = left;
+            }
+            if right < len && self.heap[right].count < self.heap[smallest].count {
+                smallest = right;
+            }
+            if smallest == idx {
+                break;
+            }
+            self.swap(idx, smallest);
+            idx = smallest;
+        }
+    }
+}
+
+struct Shard<K: Hash + Eq + Clone> {
+    sketch: SpaceSaving<K>,
+    last_decay: Instant,
+}
+
+impl<K: Hash + Eq + Clone> Shard<K> {
+    fn decay(&mut self, decay_window: Duration, now: Instant) {
+        let elapsed = now.duration_since(self.last_decay).as_secs();
+        let windows = elapsed / cmp::max(1, decay_window.as_secs());
+        if windows == 0 {
+            return;
+        }
+        let shift = cmp::min(windows, 64) as u32;
+        self.sketch.decay(shift);
+        if shift >= 64 {
+            self.last_decay = now;
+        } else {
+            self.last_decay += decay_window * shift;
+        }
+    }
+}
+
+// Keys are spread over independent sketches by hash, so that listeners
+// recording different names or clients rarely contend for the same lock.
+struct Shards<K: Hash + Eq + Clone> {
+    shards: Vec<Mutex<Shard<K>>>,
+}
+
+impl<K: Hash + Eq + Clone> Shards<K> {
+    fn new(capacity: usize) -> Shards<K> {
+        let shard_capacity = (capacity + HEAVY_HITTERS_SHARDS - 1) / HEAVY_HITTERS_SHARDS;
+        let now = Instant::now();
+        let shards = (0..HEAVY_HITTERS_SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    sketch: SpaceSaving::new(shard_capacity),
+                    last_decay: now,
+                })
+            })
+            .collect();
+        Shards { shards: shards }
+    }
+
+    fn insert<Q: ?Sized>(&self, key: &Q, decay_window: Duration, now: Instant)
+        where K: Borrow<Q>,
+              Q: Hash + Eq + ToOwned<Owned = K>
+    {
+        let mut hs = SipHasher13::new();
+        key.hash(&mut hs);
+        let mut shard = self.shards[hs.finish() as usize % HEAVY_HITTERS_SHARDS].lock().unwrap();
+        shard.decay(decay_window, now);
+        shard.sketch.insert(key);
+    }
+
+    fn top(&self, n: usize, decay_window: Duration, now: Instant) -> Vec<Slot<K>> {
+        let mut slots = Vec::new();
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.decay(decay_window, now);
+            slots.extend(shard.sketch.top(n).into_iter().map(|slot| {
+                Slot {
+                    key: slot.key.clone(),
+                    count: slot.count,
+                    error: slot.error,
+                }
+            }));
+        }
+        slots.sort_by(|a, b| b.count.cmp(&a.count));
+        slots.truncate(n);
+        slots
+    }
+}
+
+#[derive(Clone)]
+pub struct HeavyHitters {
+    enabled: bool,
+    top: usize,
+    decay_window: Duration,
+    qnames: Arc<Shards<Vec<u8>>>,
+    clients: Arc<Shards<IpAddr>>,
+}
+
+impl HeavyHitters {
+    pub fn new(config: &Config) -> HeavyHitters {
+        let capacity = if config.heavy_hitters_enabled {
+            config.heavy_hitters_capacity
+        } else {
+            0
+        };
+        HeavyHitters {
+            enabled: config.heavy_hitters_enabled,
+            top: config.heavy_hitters_top,
+            decay_window: Duration::from_secs(config.heavy_hitters_decay_window),
+            qnames: Arc::new(Shards::new(capacity)),
+            clients: Arc::new(Shards::new(capacity)),
+        }
+    }
+
+    pub fn record(&self, client_ip: IpAddr, qname: &[u8]) {
+        if !self.enabled {
+            return;
+        }
+        let mut qname_lc_buf = [0u8; dns::DNS_MAX_HOSTNAME_LEN];
+        if qname.len() > qname_lc_buf.len() {
+            return;
+        }
+        let qname_lc = &mut qname_lc_buf[..qname.len()];
+        for (c_lc, &c) in qname_lc.iter_mut().zip(qname) {
+            *c_lc = match c {
+                c @ 0x41...0x5a => c | 0x20,
+                c => c,
+            };
+        }
+        let client_prefix = client_prefix(client_ip,
+                                          HEAVY_HITTERS_IPV4_PREFIX_LEN,
+                                          HEAVY_HITTERS_IPV6_PREFIX_LEN);
+        let now = Instant::now();
+        self.qnames.insert(&qname_lc[..], self.decay_window, now);
+        self.clients.insert(&client_prefix, self.decay_window, now);
+    }
+
+    pub fn to_json(&self) -> String {
+        let now = Instant::now();
+        let qnames: Vec<String> = self.qnames
+            .top(self.top, self.decay_window, now)
+            .iter()
+            .map(|slot| {
+                let mut qname = dns::qname_to_string(&slot.key);
+                if qname.is_empty() {
+                    qname.push('.');
+                }
+                format!("{{\"name\":{},\"count\":{},\"error\":{}}}",
+                        json_string(&qname),
+                        slot.count,
+                        slot.error)
+            })
+            .collect();
+        let clients: Vec<String> = self.clients
+            .top(self.top, self.decay_window, now)
+            .iter()
+            .map(|slot| {
+                let prefix_len = match slot.key {
+                    IpAddr::V4(_) => HEAVY_HITTERS_IPV4_PREFIX_LEN,
+                    IpAddr::V6(_) => HEAVY_HITTERS_IPV6_PREFIX_LEN,
+                };
+                format!("{{\"prefix\":\"{}/{}\",\"count\":{},\"error\":{}}}",
+                        slot.key,
+                        prefix_len,
+                        slot.count,
+                        slot.error)
+            })
+            .collect();
+        format!("{{\"enabled\":{},\"decay_window\":{},\"qnames\":[{}],\"clients\":[{}]}}",
+                self.enabled,
+                self.decay_window.as_secs(),
+                qnames.join(","),
+                clients.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Shard, SpaceSaving};
+    use std::time::{Duration, Instant};
+
+    fn check_heap(sketch: &SpaceSaving<Vec<u8>>) {
+        assert_eq!(sketch.positions.len(), sketch.heap.len());
+        for (idx, slot) in sketch.heap.iter().enumerate() {
+            assert_eq!(sketch.positions[&slot.key], idx);
+            if idx > 0 {
+                assert!(sketch.heap[(idx - 1) / 2].count <= slot.count);
+            }
+        }
+    }
+
+    fn top(sketch: &SpaceSaving<Vec<u8>>, n: usize) -> Vec<(&[u8], u64, u64)> {
+        sketch.top(n).into_iter().map(|slot| (&slot.key[..], slot.count, slot.error)).collect()
+    }
+
+    #[test]
+    fn test_space_saving_counts() {
+        let mut sketch = SpaceSaving::new(4);
+        let keys: &[&[u8]] = &[b"a", b"b", b"a", b"c", b"a", b"b"];
+        for key in keys {
+            sketch.insert(*key);
+            check_heap(&sketch);
+        }
+        assert_eq!(top(&sketch, 10),
+                   vec![(&b"a"[..], 3, 0), (&b"b"[..], 2, 0), (&b"c"[..], 1, 0)]);
+        assert_eq!(top(&sketch, 1), vec![(&b"a"[..], 3, 0)]);
+    }
+
+    #[test]
+    fn test_space_saving_eviction() {
+        let mut sketch = SpaceSaving::new(2);
+        let keys: &[&[u8]] = &[b"a", b"a", b"a", b"b", b"c"];
+        for key in keys {
+            sketch.insert(*key);
+            check_heap(&sketch);
+        }
+        assert!(!sketch.positions.contains_key(&b"b"[..]));
+        assert_eq!(top(&sketch, 10), vec![(&b"a"[..], 3, 0), (&b"c"[..], 2, 1)]);
+        sketch.insert(&b"d"[..]);
+        check_heap(&sketch);
+        let mut slots = top(&sketch, 10);
+        slots.sort();
+        assert_eq!(slots, vec![(&b"a"[..], 3, 0), (&b"d"[..], 3, 2)]);
+    }
+
+    #[test]
+    fn test_space_saving_zero_capacity() {
+        let mut sketch: SpaceSaving<Vec<u8>> = SpaceSaving::new(0);
+        sketch.insert(&b"a"[..]);
+        assert!(sketch.top(10).is_empty());
+    }
+
+    #[test]
+    fn test_space_saving_decay() {
+        let mut sketch = SpaceSaving::new(4);
+        for _ in 0..5 {
+            sketch.insert(&b"a"[..]);
+        }
+        sketch.insert(&b"b"[..]);
+        sketch.decay(1);
+        check_heap(&sketch);
+        assert_eq!(top(&sketch, 10), vec![(&b"a"[..], 2, 0)]);
+        sketch.decay(64);
+        check_heap(&sketch);
+        assert!(sketch.top(10).is_empty());
+    }
+
+    #[test]
+    fn test_shard_decay_windows() {
+        let start = Instant::now();
+        let decay_window = Duration::from_secs(10);
+        let mut shard = Shard {
+            sketch: SpaceSaving::new(4),
+            last_decay: start,
+        };
+        for _ in 0..8 {
+            shard.sketch.insert(&b"a"[..]);
+        }
+        shard.decay(decay_window, start + Duration::from_secs(9));
+        assert_eq!(shard.sketch.heap[0].count, 8);
+        assert_eq!(shard.last_decay, start);
+        shard.decay(decay_window, start + Duration::from_secs(25));
+        assert_eq!(shard.sketch.heap[0].count, 2);
+        assert_eq!(shard.last_decay, start + Duration::from_secs(20));
+        shard.decay(decay_window, start + Duration::from_secs(10_000));
+        assert_eq!(shard.sketch.heap[0].count, 0);
+        assert_eq!(shard.last_decay, start + Duration::from_secs(10_000));
+    }
+}
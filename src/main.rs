@@ -7,13 +7,16 @@ extern crate clockpro_cache;
 extern crate bytes;
 extern crate clap;
 extern crate env_logger;
+extern crate libc;
 extern crate mio;
 extern crate nix;
 extern crate privdrop;
 extern crate rand;
+extern crate rustls;
 extern crate siphasher;
 extern crate slab;
 extern crate toml;
+extern crate webpki_roots;
 
 #[cfg(feature = "webservice")]
 extern crate hyper;
@@ -21,14 +24,28 @@ extern crate hyper;
 #[macro_use]
 extern crate prometheus;
 
+mod acl;
 mod cache;
 mod client_query;
 mod client;
+mod client_limiter;
 mod config;
 mod dns;
+mod heavy_hitters;
+mod json;
+mod net;
+mod query_log;
 mod resolver;
+mod rrl;
+mod signals;
 mod tcp_listener;
+mod tls_listener;
+mod token_bucket;
+mod udp_batch;
 mod udp_listener;
+mod upstream;
+mod upstream_tcp;
+mod upstream_tls;
 mod varz;
 
 #[cfg(feature = "webservice")]
@@ -36,13 +53,24 @@ mod webservice;
 
 use cache::Cache;
 use clap::{Arg, App};
+use client_limiter::ClientLimiter;
+use client_query::ClientQuery;
 use config::Config;
+use heavy_hitters::HeavyHitters;
+use mio::channel;
 use privdrop::PrivDrop;
+use query_log::QueryLog;
 use resolver::*;
+use rrl::Rrl;
+use std::fs;
 use std::net::UdpSocket;
+use std::process;
 use std::sync::Arc;
 use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::{Duration, Instant};
 use tcp_listener::*;
+use tls_listener::*;
 use udp_listener::*;
 use varz::*;
 
@@ -55,28 +83,49 @@ const DNS_MAX_UDP_SIZE: usize = 4096;
 const DNS_QUERY_MAX_SIZE: usize = 283;
 const DNS_QUERY_MIN_SIZE: usize = 17;
 const DNS_UDP_NOEDNS0_MAX_SIZE: usize = 512;
-const HEALTH_CHECK_MS: u64 = 10 * 1000;
-const MAX_ACTIVE_QUERIES: usize = 100_000;
+const MAIN_LOOP_TICK_MS: u64 = 500;
+const CLIENT_LIMITER_SHARDS: usize = 16;
+const CLIENT_LIMITER_TABLE_SIZE: usize = 100_000;
+const HEAVY_HITTERS_IPV4_PREFIX_LEN: u8 = 24;
+const HEAVY_HITTERS_IPV6_PREFIX_LEN: u8 = 56;
+const HEAVY_HITTERS_SHARDS: usize = 16;
 const MAX_CLIENTS_WAITING_FOR_QUERY: usize = 1_000;
 const MAX_EVENTS_PER_BATCH: usize = 1024;
-const MAX_TCP_CLIENTS: usize = 1_000;
 const MAX_TCP_HASH_DISTANCE: usize = 10;
-const MAX_TCP_IDLE_MS: u64 = 10 * 1000;
-const MAX_WAITING_CLIENTS: usize = MAX_ACTIVE_QUERIES * 10;
-const FAILURE_TTL: u32 = 30;
-const UDP_BUFFER_SIZE: usize = 16 * 1024 * 1024;
-const UPSTREAM_INITIAL_TIMEOUT_MS: u64 = 1 * 1000;
-const UPSTREAM_MAX_TIMEOUT_MS: u64 = 8 * 1000;
+const MAX_TCP_PIPELINED_QUERIES: usize = 16;
+const MAX_UPSTREAM_TCP_QUERIES: usize = 1_000;
+const MAX_UPSTREAM_TLS_CONNECTIONS: usize = 1_000;
+// Upstream queries advertise a 64 KiB EDNS payload, so every slot of the
+// upstream receive batch needs a DNS_MAX_SIZE buffer. A MAX_EVENTS_PER_BATCH
+// batch would pin 64 MiB per resolver; 64 slots keep it at 4 MiB.
+const MAX_UPSTREAM_UDP_BATCH: usize = 64;
+const MAX_WAITING_CLIENTS_PER_QUERY: usize = 10;
+const QUERY_LOG_QUEUE_SIZE: usize = 10_000;
+const RRL_SHARDS: usize = 16;
+const RRL_TABLE_SIZE: usize = 100_000;
+const SERVE_STALE_TTL: u32 = 30;
+const UPSTREAM_TCP_TIMEOUT_MS: u64 = 5 * 1000;
+const UPSTREAM_TLS_CONNECTIONS_PER_SERVER: usize = 4;
+const UPSTREAM_TLS_IDLE_TIMEOUT_MS: u64 = 30 * 1000;
 const UPSTREAM_TIMEOUT_MS: u64 = 10 * 1000;
 
+// DoH requests block a webservice thread until the resolver answers, so they
+// get a short deadline of their own and enough threads to keep a slow
+// upstream from stalling the other requests and the metrics scrape.
 #[cfg(feature = "webservice")]
-const WEBSERVICE_THREADS: usize = 1;
+const DOH_TIMEOUT_MS: u64 = 2_500;
+#[cfg(feature = "webservice")]
+const WEBSERVICE_THREADS: usize = 16;
 
 pub struct RPDNSContext {
     pub config: Config,
-    pub udp_socket: UdpSocket,
-    pub listen_addr: String,
+    pub udp_sockets: Vec<UdpSocket>,
+    pub listen_addrs: Vec<String>,
     pub cache: Cache,
+    pub rrl: Rrl,
+    pub client_limiter: ClientLimiter,
+    pub heavy_hitters: HeavyHitters,
+    pub query_log: QueryLog,
     pub varz: Arc<Varz>,
 }
 
@@ -84,12 +133,15 @@ struct RPDNS;
 
 impl RPDNS {
     #[cfg(feature = "webservice")]
-    fn webservice_start(rpdns_context: &RPDNSContext) {
-        WebService::spawn(rpdns_context).expect("Unable to spawn the web service");
+    fn webservice_start(rpdns_context: &RPDNSContext,
+                        resolver_tx: channel::SyncSender<ClientQuery>) {
+        WebService::spawn(rpdns_context, resolver_tx).expect("Unable to spawn the web service");
     }
 
     #[cfg(not(feature = "webservice"))]
-    fn webservice_start(_rpdns_context: &RPDNSContext) {}
+    fn webservice_start(_rpdns_context: &RPDNSContext,
+                        _resolver_tx: channel::SyncSender<ClientQuery>) {
+    }
 
     fn privileges_drop(config: &Config) {
         let mut pd = PrivDrop::default();
@@ -105,37 +157,165 @@ impl RPDNS {
         pd.apply().unwrap();
     }
 
-    fn new(config: Config) -> RPDNS {
+    fn cache_snapshot_load(cache: &mut Cache, config: &Config) {
+        let snapshot_file = match config.cache_snapshot_file {
+            None => return,
+            Some(ref snapshot_file) => snapshot_file,
+        };
+        match cache.load_snapshot(snapshot_file) {
+            Ok(count) => info!("{} entries loaded from the cache snapshot", count),
+            Err(e) => info!("Cache snapshot [{}] not loaded: {}", snapshot_file, e),
+        }
+    }
+
+    fn cache_snapshot_save(cache: &Cache, config: &Config) {
+        let snapshot_file = match config.cache_snapshot_file {
+            None => return,
+            Some(ref snapshot_file) => config.chrooted_path(snapshot_file),
+        };
+        match cache.save_snapshot(&snapshot_file) {
+            Ok(count) => info!("{} entries saved to the cache snapshot", count),
+            Err(e) => warn!("Unable to save the cache snapshot [{}]: {}", snapshot_file, e),
+        }
+    }
+
+    fn config_reload(rpdns_context: &RPDNSContext,
+                     config: &Config,
+                     config_file: &str,
+                     resolver_command_tx: &channel::SyncSender<ResolverCommand>)
+                     -> Option<Config> {
+        info!("Reloading the configuration");
+        let new_config = match Config::from_path(config_file) {
+            Err(err) => {
+                error!("The configuration couldn't be reloaded -- [{}]: [{}]",
+                       config_file,
+                       err);
+                return None;
+            }
+            Ok(new_config) => new_config,
+        };
+        for setting in config.restart_required(&new_config) {
+            warn!("Changes to [{}] require a restart to take effect", setting);
+        }
+        rpdns_context.cache.reload(new_config.clone());
+        if resolver_command_tx.send(ResolverCommand::Reload(new_config.clone())).is_err() {
+            error!("Unable to send the new configuration to the resolver");
+        }
+        info!("Configuration reloaded");
+        Some(new_config)
+    }
+
+    fn shutdown(config: &Config,
+                resolver_command_tx: &channel::SyncSender<ResolverCommand>,
+                listener_threads: Vec<thread::JoinHandle<()>>) {
+        let (drained_tx, drained_rx) = sync_channel::<()>(1);
+        if resolver_command_tx.send(ResolverCommand::Shutdown(drained_tx)).is_ok() {
+            match drained_rx.recv_timeout(Duration::from_secs(config.shutdown_timeout)) {
+                Ok(()) => info!("All pending queries have been answered"),
+                Err(_) => warn!("Shutdown timeout reached with queries still pending"),
+            }
+        }
+        for listener_thread in listener_threads {
+            let _ = listener_thread.join();
+        }
+    }
+
+    fn run(rpdns_context: &RPDNSContext,
+           config_file: &str,
+           resolver_command_tx: channel::SyncSender<ResolverCommand>,
+           listener_threads: Vec<thread::JoinHandle<()>>) {
+        let mut config = rpdns_context.config.clone();
+        let config_file = config.chrooted_path(config_file);
+        let mut last_snapshot = Instant::now();
+        while !signals::terminate_requested() {
+            thread::sleep(Duration::from_millis(MAIN_LOOP_TICK_MS));
+            if signals::reload_requested() {
+                if let Some(new_config) = Self::config_reload(rpdns_context,
+                                                              &config,
+                                                              &config_file,
+                                                              &resolver_command_tx) {
+                    config = new_config;
+                }
+            }
+            if config.cache_snapshot_interval > 0 &&
+               last_snapshot.elapsed() >= Duration::from_secs(config.cache_snapshot_interval) {
+                Self::cache_snapshot_save(&rpdns_context.cache, &config);
+                last_snapshot = Instant::now();
+            }
+        }
+        info!("Termination requested");
+        Self::shutdown(&config, &resolver_command_tx, listener_threads);
+        Self::cache_snapshot_save(&rpdns_context.cache, &config);
+        info!("EdgeDNS has been shut down");
+    }
+
+    fn new(config: Config, config_file: &str) -> RPDNS {
+        signals::install();
         let varz = Arc::new(Varz::new());
-        let cache = Cache::new(config.clone());
-        let udp_socket = socket_udp_bound(&config.listen_addr)
-            .expect("Unable to create a client socket");
+        let mut cache = Cache::new(config.clone());
+        Self::cache_snapshot_load(&mut cache, &config);
+        let mut udp_sockets = Vec::new();
+        for listen_addr in &config.listen_addrs {
+            for _ in 0..config.udp_listener_threads {
+                let udp_socket = socket_udp_bound(listen_addr, config.udp_buffer_size)
+                    .expect("Unable to create a client socket");
+                udp_sockets.push(udp_socket);
+            }
+        }
+        let query_log = QueryLog::new(&config, varz.clone())
+            .expect("Unable to open the query log");
         let rpdns_context = RPDNSContext {
             config: config.clone(),
-            udp_socket: udp_socket,
-            listen_addr: config.listen_addr.to_owned(),
+            udp_sockets: udp_sockets,
+            listen_addrs: config.listen_addrs.clone(),
             cache: cache,
+            rrl: Rrl::new(&config, varz.clone()),
+            client_limiter: ClientLimiter::new(&config, varz.clone()),
+            heavy_hitters: HeavyHitters::new(&config),
+            query_log: query_log,
             varz: varz,
         };
-        let resolver_tx = Resolver::spawn(&rpdns_context).expect("Unable to spawn the resolver");
+        let (resolver_tx, resolver_command_tx) =
+            Resolver::spawn(&rpdns_context).expect("Unable to spawn the resolver");
         if config.webservice_enabled {
-            Self::webservice_start(&rpdns_context);
+            Self::webservice_start(&rpdns_context, resolver_tx.clone());
         }
         let (service_ready_tx, service_ready_rx) = sync_channel::<u8>(1);
-        let udp_listener = UdpListener::spawn(&rpdns_context,
-                                              resolver_tx.clone(),
-                                              service_ready_tx.clone())
-            .expect("Unable to spawn a UDP listener");
-        service_ready_rx.recv().unwrap();
-        let tcp_listener = TcpListener::spawn(&rpdns_context,
-                                              resolver_tx.clone(),
-                                              service_ready_tx.clone())
-            .expect("Unable to spawn a TCP listener");
-        service_ready_rx.recv().unwrap();
+        let mut listener_threads = Vec::new();
+        for udp_socket_idx in 0..rpdns_context.udp_sockets.len() {
+            let udp_listener_th = UdpListener::spawn(&rpdns_context,
+                                                     udp_socket_idx,
+                                                     resolver_tx.clone(),
+                                                     service_ready_tx.clone())
+                .expect("Unable to spawn a UDP listener");
+            listener_threads.push(udp_listener_th);
+            service_ready_rx.recv().unwrap();
+        }
+        for listen_addr in &rpdns_context.listen_addrs {
+            let tcp_listener_th = TcpListener::spawn(&rpdns_context,
+                                                     listen_addr.clone(),
+                                                     resolver_tx.clone(),
+                                                     service_ready_tx.clone())
+                .expect("Unable to spawn a TCP listener");
+            listener_threads.push(tcp_listener_th);
+            service_ready_rx.recv().unwrap();
+        }
+        if config.tls_enabled {
+            let tls_listener_th =
+                TlsListener::spawn(&rpdns_context, resolver_tx.clone(), service_ready_tx.clone())
+                    .expect("Unable to spawn a TLS listener");
+            listener_threads.push(tls_listener_th);
+            service_ready_rx.recv().unwrap();
+        }
+        let config_file = fs::canonicalize(config_file)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| config_file.to_owned());
         Self::privileges_drop(&config);
         info!("EdgeDNS is ready to process requests");
-        let _ = udp_listener.join();
-        let _ = tcp_listener.join();
+        Self::run(&rpdns_context,
+                  &config_file,
+                  resolver_command_tx,
+                  listener_threads);
 
         RPDNS
     }
@@ -155,6 +335,9 @@ fn main() {
             .help("Path to the edgedns.toml config file")
             .takes_value(true)
             .required(true))
+        .arg(Arg::with_name("check_config")
+            .long("check-config")
+            .help("Validate the configuration file and exit"))
         .get_matches();
 
     let config_file = match matches.value_of("config_file") {
@@ -169,9 +352,13 @@ fn main() {
             error!("The configuration couldn't be loaded -- [{}]: [{}]",
                    config_file,
                    err);
-            return;
+            process::exit(1);
         }
         Ok(config) => config,
     };
-    RPDNS::new(config);
+    if matches.is_present("check_config") {
+        println!("The configuration file [{}] is valid", config_file);
+        return;
+    }
+    RPDNS::new(config, config_file);
 }
@@ -0,0 +1,19 @@
+use libc;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+pub fn set_ipv6_only(socket_fd: RawFd) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(socket_fd,
+                         libc::IPPROTO_IPV6,
+                         libc::IPV6_V6ONLY,
+                         &on as *const _ as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,261 @@
+use client_query::ClientQueryProtocol;
+use config::Config;
+use dns::{self, NormalizedQuestion};
+use json::json_string;
+use rand::random;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use varz::{duration_secs, Varz};
+
+use super::QUERY_LOG_QUEUE_SIZE;
+
+#[derive(Copy, Clone, Debug)]
+pub enum CacheStatus<'t> {
+    None,
+    Hit,
+    Stale,
+    Miss(Option<&'t str>),
+}
+
+impl<'t> CacheStatus<'t> {
+    fn name(&self) -> &'static str {
+        match *self {
+            CacheStatus::None => "none",
+            CacheStatus::Hit => "hit",
+            CacheStatus::Stale => "stale",
+            CacheStatus::Miss(_) => "miss",
+        }
+    }
+}
+
+struct QueryLogEntry {
+    ts: SystemTime,
+    client_ip: IpAddr,
+    proto: &'static str,
+    qname: Vec<u8>,
+    qtype: u16,
+    rcode: u8,
+    cache: &'static str,
+    upstream: Option<String>,
+    latency: Duration,
+    size: usize,
+}
+
+impl QueryLogEntry {
+    fn to_json(&self) -> String {
+        let ts = self.ts.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let mut qname = dns::qname_to_string(&dns::qname_lc(&self.qname));
+        if qname.is_empty() {
+            qname.push('.');
+        }
+        let upstream = match self.upstream {
+            None => "null".to_owned(),
+            Some(ref upstream) => json_string(upstream),
+        };
+        format!("{{\"ts\":{}.{:03},\"client\":\"{}\",\"protocol\":\"{}\",\"qname\":{},\
+                 \"qtype\":{},\"rcode\":{},\"cache\":\"{}\",\"upstream\":{},\
+                 \"latency\":{:.6},\"size\":{}}}\n",
+                ts.as_secs(),
+                ts.subsec_nanos() / 1_000_000,
+                self.client_ip,
+                self.proto,
+                json_string(&qname),
+                self.qtype,
+                self.rcode,
+                self.cache,
+                upstream,
+                duration_secs(self.latency),
+                self.size)
+    }
+}
+
+struct QueryLogFile {
+    path: String,
+    rotation_path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+    rotation_failed: bool,
+}
+
+impl QueryLogFile {
+    fn open(path: &str,
+            rotation_path: String,
+            max_size: u64,
+            max_files: usize)
+            -> io::Result<QueryLogFile> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        let size = try!(file.metadata()).len();
+        Ok(QueryLogFile {
+            path: path.to_owned(),
+            rotation_path: rotation_path,
+            file: file,
+            size: size,
+            max_size: max_size,
+            max_files: max_files,
+            rotation_failed: false,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.rotation_path;
+        for i in (1..self.max_files).rev() {
+            let _ = fs::rename(format!("{}.{}", path, i), format!("{}.{}", path, i + 1));
+        }
+        try!(fs::rename(path, format!("{}.1", path)));
+        self.file = try!(OpenOptions::new().create(true).append(true).open(path));
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            match self.rotate() {
+                Ok(()) => self.rotation_failed = false,
+                Err(e) => {
+                    if !self.rotation_failed {
+                        warn!("Unable to rotate the query log [{}]: {} - Appending to the \
+                               current file",
+                              self.path,
+                              e);
+                        self.rotation_failed = true;
+                    }
+                    self.size = 0;
+                }
+            }
+        }
+        try!(self.file.write_all(line));
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+struct QueryLogSocket {
+    socket: UnixDatagram,
+    path: String,
+    send_failed: bool,
+}
+
+impl QueryLogSocket {
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        match self.socket.send_to(line, &self.path) {
+            Ok(_) => {
+                self.send_failed = false;
+                Ok(())
+            }
+            Err(e) => {
+                if !self.send_failed {
+                    warn!("Unable to send query log entries to [{}]: {}", self.path, e);
+                    self.send_failed = true;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+enum QueryLogSink {
+    File(QueryLogFile),
+    Socket(QueryLogSocket),
+}
+
+impl QueryLogSink {
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        match *self {
+            QueryLogSink::File(ref mut query_log_file) => query_log_file.write(line),
+            QueryLogSink::Socket(ref mut query_log_socket) => query_log_socket.write(line),
+        }
+    }
+
+    fn run(mut self, query_log_rx: Receiver<QueryLogEntry>) {
+        for entry in query_log_rx.iter() {
+            if let Err(e) = self.write(entry.to_json().as_bytes()) {
+                debug!("Unable to write to the query log: {}", e);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct QueryLog {
+    sample_rate: u32,
+    query_log_tx: Option<SyncSender<QueryLogEntry>>,
+    varz: Arc<Varz>,
+}
+
+impl QueryLog {
+    pub fn new(config: &Config, varz: Arc<Varz>) -> io::Result<QueryLog> {
+        if !config.query_log_enabled {
+            return Ok(QueryLog {
+                sample_rate: config.query_log_sample_rate,
+                query_log_tx: None,
+                varz: varz,
+            });
+        }
+        let sink = if let Some(ref path) = config.query_log_socket {
+            QueryLogSink::Socket(QueryLogSocket {
+                socket: try!(UnixDatagram::unbound()),
+                path: config.chrooted_path(path),
+                send_failed: false,
+            })
+        } else {
+            let path = config.query_log_file.as_ref().expect("No query log file");
+            QueryLogSink::File(try!(QueryLogFile::open(path,
+                                                       config.chrooted_path(path),
+                                                       config.query_log_max_file_size,
+                                                       config.query_log_max_files)))
+        };
+        let (query_log_tx, query_log_rx) = mpsc::sync_channel(QUERY_LOG_QUEUE_SIZE);
+        try!(thread::Builder::new()
+            .name("query_log".to_owned())
+            .spawn(move || sink.run(query_log_rx)));
+        info!("Query log is ready");
+        Ok(QueryLog {
+            sample_rate: config.query_log_sample_rate,
+            query_log_tx: Some(query_log_tx),
+            varz: varz,
+        })
+    }
+
+    pub fn log(&self,
+               client_ip: IpAddr,
+               proto: ClientQueryProtocol,
+               normalized_question: &NormalizedQuestion,
+               packet: &[u8],
+               cache_status: CacheStatus,
+               ts: Instant) {
+        let query_log_tx = match self.query_log_tx {
+            None => return,
+            Some(ref query_log_tx) => query_log_tx,
+        };
+        if self.sample_rate > 1 && random::<u32>() % self.sample_rate != 0 {
+            return;
+        }
+        let upstream = match cache_status {
+            CacheStatus::Miss(upstream) => upstream.map(|upstream| upstream.to_owned()),
+            _ => None,
+        };
+        let entry = QueryLogEntry {
+            ts: SystemTime::now(),
+            client_ip: client_ip,
+            proto: proto.name(),
+            qname: normalized_question.qname.clone(),
+            qtype: normalized_question.qtype,
+            rcode: dns::rcode(packet),
+            cache: cache_status.name(),
+            upstream: upstream,
+            latency: ts.elapsed(),
+            size: packet.len(),
+        };
+        if let Err(TrySendError::Full(_)) = query_log_tx.try_send(entry) {
+            self.varz.query_log_dropped.inc();
+        }
+    }
+}
@@ -3,43 +3,63 @@ use client_query::*;
 use config::Config;
 use dns::{NormalizedQuestion, NormalizedQuestionKey, NormalizedQuestionMinimal,
           build_query_packet, normalize, tid, set_tid, overwrite_qname, build_tc_packet,
-          build_health_check_packet, build_servfail_packet, min_ttl, set_ttl, rcode,
-          DNS_HEADER_SIZE, DNS_RCODE_SERVFAIL};
+          build_health_check_packet, build_servfail_packet, clamp_ttls, is_negative, min_ttl,
+          set_ttl, rcode, tc, DNS_HEADER_SIZE, DNS_RCODE_SERVFAIL};
 use mio;
 use mio::*;
+use net::set_ipv6_only;
 use nix::fcntl::FcntlArg::F_SETFL;
 use nix::fcntl::{fcntl, O_NONBLOCK};
 use nix::sys::socket::{bind, setsockopt, sockopt, AddressFamily, SockFlag, SockType, SockLevel,
                        SockAddr, socket, InetAddr};
+use query_log::{CacheStatus, QueryLog};
 use rand::distributions::{IndependentSample, Range};
 use rand;
 use siphasher::sip::SipHasher13;
+use rrl::{Rrl, RrlAction};
+use slab;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::io;
-use std::net::{UdpSocket, Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::os::unix::io::{RawFd, FromRawFd};
-use std::str::FromStr;
+use std::mem;
+use std::net::{UdpSocket, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd, FromRawFd};
 use std::sync::Arc;
+use std::sync::mpsc;
 use std::thread;
 use std::time::{Duration, Instant};
 use std::{u64, usize};
 use super::RPDNSContext;
-use varz::Varz;
+use udp_batch::{self, RecvBatch};
+use upstream::UpstreamAddr;
+use upstream_tcp::UpstreamTcpQuery;
+use upstream_tls::{UpstreamTlsPool, UPSTREAM_TLS_TOK_BASE};
+use varz::{duration_secs, Varz};
 
-use super::{DNS_MAX_SIZE, DNS_QUERY_MIN_SIZE, UDP_BUFFER_SIZE, UPSTREAM_TIMEOUT_MS,
-            UPSTREAM_MAX_TIMEOUT_MS, MAX_ACTIVE_QUERIES, MAX_CLIENTS_WAITING_FOR_QUERY,
-            MAX_EVENTS_PER_BATCH, MAX_WAITING_CLIENTS, HEALTH_CHECK_MS,
-            UPSTREAM_INITIAL_TIMEOUT_MS, FAILURE_TTL};
+use super::{DNS_MAX_SIZE, DNS_QUERY_MIN_SIZE, UPSTREAM_TIMEOUT_MS, MAX_CLIENTS_WAITING_FOR_QUERY,
+            MAX_EVENTS_PER_BATCH, MAX_WAITING_CLIENTS_PER_QUERY, MAX_UPSTREAM_TCP_QUERIES,
+            MAX_UPSTREAM_UDP_BATCH, SERVE_STALE_TTL, UPSTREAM_TCP_TIMEOUT_MS,
+            UPSTREAM_TLS_IDLE_TIMEOUT_MS};
 
 const NOTIFY_TOK: Token = Token(usize::MAX - 1);
 const TIMER_TOK: Token = Token(usize::MAX - 2);
+const COMMAND_TOK: Token = Token(usize::MAX - 3);
+const MAX_PENDING_COMMANDS: usize = 16;
+const UPSTREAM_TCP_TOK_BASE: usize = 131072;
+
+type Slab<T> = slab::Slab<T, usize>;
+
+pub enum ResolverCommand {
+    Reload(Config),
+    Shutdown(mpsc::SyncSender<()>),
+}
 
 #[derive(Clone, Debug)]
 pub struct ResolverResponse {
     pub client_tok: Token,
     pub response: Vec<u8>,
     pub dnssec: bool,
+    pub ttl: u32,
 }
 
 struct ExtUdpSocketTuple {
@@ -47,26 +67,144 @@ struct ExtUdpSocketTuple {
     ext_udp_socket: udp::UdpSocket,
 }
 
+struct ExtUdpSockets {
+    tuples: Vec<ExtUdpSocketTuple>,
+    v4: Vec<usize>,
+    v6: Vec<usize>,
+}
+
+impl ExtUdpSockets {
+    fn bind(mio_poll: &mio::Poll,
+            ports: u16,
+            buffer_size: usize,
+            v4: bool,
+            v6: bool)
+            -> ExtUdpSockets {
+        let mut ext_udp_sockets = ExtUdpSockets {
+            tuples: Vec::new(),
+            v4: Vec::new(),
+            v6: Vec::new(),
+        };
+        if v4 {
+            ext_udp_sockets.v4 = ext_udp_sockets.bind_pool(mio_poll,
+                                                           ports,
+                                                           buffer_size,
+                                                           IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
+            if ext_udp_sockets.v4.is_empty() {
+                panic!("Couldn't bind any IPv4 ports");
+            }
+        }
+        if v6 {
+            ext_udp_sockets.v6 =
+                ext_udp_sockets.bind_pool(mio_poll,
+                                          ports,
+                                          buffer_size,
+                                          IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)));
+            if ext_udp_sockets.v6.is_empty() {
+                panic!("Couldn't bind any IPv6 ports");
+            }
+        }
+        ext_udp_sockets
+    }
+
+    fn bind_pool(&mut self,
+                 mio_poll: &mio::Poll,
+                 ports: u16,
+                 buffer_size: usize,
+                 ip: IpAddr)
+                 -> Vec<usize> {
+        let mut pool = Vec::new();
+        for port in 1024..1024 + ports {
+            if (port + 1) % 1024 == 0 {
+                info!("Binding ports on {}... {}/{}", ip, port, ports)
+            }
+            if let Ok(ext_udp_socket) = mio_socket_udp_bound(ip, port, buffer_size) {
+                mio_poll.register(&ext_udp_socket,
+                              Token(self.tuples.len()),
+                              Ready::readable(),
+                              PollOpt::edge())
+                    .unwrap();
+                let ext_udp_socket_tuple = ExtUdpSocketTuple {
+                    local_port: port,
+                    ext_udp_socket: ext_udp_socket,
+                };
+                pool.push(self.tuples.len());
+                self.tuples.push(ext_udp_socket_tuple);
+            }
+        }
+        pool
+    }
+
+    fn random_for(&self, socket_addr: &SocketAddr) -> Option<&ExtUdpSocketTuple> {
+        let pool = match *socket_addr {
+            SocketAddr::V4(_) => &self.v4,
+            SocketAddr::V6(_) => &self.v6,
+        };
+        if pool.is_empty() {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        let random_idx = Range::new(0usize, pool.len()).ind_sample(&mut rng);
+        Some(&self.tuples[pool[random_idx]])
+    }
+}
+
 struct UpstreamServer {
     remote_addr: String,
     socket_addr: SocketAddr,
+    tls_server_name: Option<String>,
     failures: u32,
     offline: bool,
 }
 
 impl UpstreamServer {
-    fn new(remote_addr: &str) -> Result<UpstreamServer, &'static str> {
-        let socket_addr = match FromStr::from_str(remote_addr) {
-            Err(_) => return Err("Unable to parse an upstream resolver address"),
-            Ok(socket_addr) => socket_addr,
-        };
-        let upstream_server = UpstreamServer {
-            remote_addr: remote_addr.to_owned(),
-            socket_addr: socket_addr,
+    fn new(upstream_addr: &UpstreamAddr) -> UpstreamServer {
+        UpstreamServer {
+            remote_addr: upstream_addr.remote_addr.clone(),
+            socket_addr: upstream_addr.socket_addr,
+            tls_server_name: upstream_addr.tls_server_name.clone(),
             failures: 0,
             offline: false,
-        };
-        Ok(upstream_server)
+        }
+    }
+
+    fn update_online_varz(&self, varz: &Varz) {
+        let online = if self.offline { 0.0 } else { 1.0 };
+        varz.upstream_server_online.with_label_values(&[self.remote_addr.as_str()]).set(online);
+    }
+
+    fn update_sent_varz(&self, varz: &Varz, res: &io::Result<Option<usize>>) {
+        let upstream_label = [self.remote_addr.as_str()];
+        match *res {
+            Ok(_) => varz.upstream_server_sent.with_label_values(&upstream_label).inc(),
+            Err(ref e) => {
+                info!("Unable to send a query to {}: {}", self.remote_addr, e);
+                varz.upstream_server_failures.with_label_values(&upstream_label).inc();
+            }
+        }
+    }
+
+    fn send_query(&self,
+                  upstream_server_idx: usize,
+                  query_packet: &[u8],
+                  ext_udp_socket_tuple: &ExtUdpSocketTuple,
+                  upstream_tls_pool: &mut UpstreamTlsPool,
+                  mio_poll: &mio::Poll)
+                  -> io::Result<Option<usize>> {
+        match self.tls_server_name {
+            None => {
+                try!(ext_udp_socket_tuple.ext_udp_socket.send_to(query_packet, &self.socket_addr));
+                Ok(None)
+            }
+            Some(ref server_name) => {
+                upstream_tls_pool.send_query(mio_poll,
+                                             upstream_server_idx,
+                                             self.socket_addr,
+                                             server_name,
+                                             query_packet)
+                    .map(Some)
+            }
+        }
     }
 }
 
@@ -74,17 +212,23 @@ pub struct Resolver {
     mio_poll: mio::Poll,
     mio_timers: timer::Timer<TimeoutToken>,
     config: Config,
-    udp_socket: UdpSocket,
+    udp_sockets: Vec<UdpSocket>,
     pending_queries: PendingQueries,
-    ext_udp_socket_tuples: Vec<ExtUdpSocketTuple>,
+    ext_udp_sockets: ExtUdpSockets,
+    recv_batch: RecvBatch,
+    upstream_tcp_queries: Slab<UpstreamTcpQuery>,
+    upstream_tls_pool: UpstreamTlsPool,
     upstream_servers: Vec<UpstreamServer>,
     upstream_servers_live: Vec<usize>,
     waiting_clients_count: usize,
     cache: Cache,
+    rrl: Rrl,
+    query_log: QueryLog,
     varz: Arc<Varz>,
     decrement_ttl: bool,
     failover: bool,
     upstream_max_failures: u32,
+    drained_tx: Option<mpsc::SyncSender<()>>,
 }
 
 struct PendingQueries {
@@ -97,8 +241,11 @@ struct ActiveQuery {
     local_port: u16,
     client_queries: Vec<ClientQuery>,
     ts: Instant,
+    sent_ts: Instant,
     delay: u64,
     upstream_server_idx: usize,
+    upstream_tcp_idx: Option<usize>,
+    upstream_tls_id: Option<usize>,
     timeout: timer::Timeout,
 }
 
@@ -112,6 +259,8 @@ impl PendingQueries {
 pub enum TimeoutToken {
     Key(NormalizedQuestionKey),
     HealthCheck,
+    UpstreamTcp(usize),
+    UpstreamTlsIdle,
 }
 
 impl Resolver {
@@ -121,14 +270,20 @@ impl Resolver {
                 self.timeout_question(normalized_question_key)
             }
             TimeoutToken::HealthCheck => self.timeout_health_check(),
+            TimeoutToken::UpstreamTcp(upstream_tcp_idx) => {
+                self.timeout_upstream_tcp(upstream_tcp_idx)
+            }
+            TimeoutToken::UpstreamTlsIdle => self.timeout_upstream_tls_idle(),
         }
     }
 
-    fn verify_active_query(&self, active_query: &ActiveQuery, packet: &[u8], client_addr: SocketAddr, local_port: u16) -> Result<(), &'static str> {
-        if local_port != active_query.local_port {
-            debug!("Got a reponse on port {} for a query sent on port {}",
-                   local_port, active_query.local_port);
-            return Err("Response on an unexpected port");
+    fn verify_active_query(&self, active_query: &ActiveQuery, packet: &[u8], client_addr: SocketAddr, local_port: Option<u16>) -> Result<(), &'static str> {
+        if let Some(local_port) = local_port {
+            if local_port != active_query.local_port {
+                debug!("Got a reponse on port {} for a query sent on port {}",
+                       local_port, active_query.local_port);
+                return Err("Response on an unexpected port");
+            }
         }
         if active_query.socket_addr != client_addr {
             info!("Sent a query to {:?} but got a response from {:?}",
@@ -145,7 +300,7 @@ impl Resolver {
         Ok(())
     }
 
-    fn dispatch_active_query(&mut self, packet: &mut [u8], normalized_question_key: &NormalizedQuestionKey, client_addr: SocketAddr, local_port: u16) {
+    fn dispatch_active_query(&mut self, packet: &mut [u8], normalized_question_key: &NormalizedQuestionKey, client_addr: SocketAddr, local_port: Option<u16>, ttl: u32) {
         let active_query = match self.pending_queries.map.get(&normalized_question_key) {
             None => {
                 debug!("No clients waiting for this query");
@@ -157,55 +312,95 @@ impl Resolver {
             debug!("Received response is not valid for the query originally sent");
             return;
         }
+        let upstream_server = self.upstream_servers.get(active_query.upstream_server_idx);
+        if let Some(upstream_server) = upstream_server {
+            let upstream_label = [upstream_server.remote_addr.as_str()];
+            self.varz.upstream_server_received.with_label_values(&upstream_label).inc();
+            self.varz
+                .upstream_rtt
+                .with_label_values(&upstream_label)
+                .observe(duration_secs(active_query.sent_ts.elapsed()));
+        }
+        let cache_status =
+            CacheStatus::Miss(upstream_server.map(|upstream_server| {
+                upstream_server.remote_addr.as_str()
+            }));
+        let mut udp_responses: Vec<(usize, Vec<(Vec<u8>, SocketAddr)>)> = Vec::new();
         let client_queries = &active_query.client_queries;
         for client_query in client_queries {
             set_tid(packet, client_query.normalized_question.tid);
             overwrite_qname(packet, &client_query.normalized_question.qname);
             self.varz.upstream_received.inc();
+            self.client_latency_observe(client_query);
             match client_query.proto {
                 ClientQueryProtocol::UDP => {
                     if client_query.ts.elapsed() <
                        Duration::from_millis(UPSTREAM_TIMEOUT_MS) {
-                        if packet.len() >
-                           client_query.normalized_question.payload_size as usize {
-                            let packet =
-                                &build_tc_packet(&client_query.normalized_question)
-                                    .unwrap();
-                            let _ = self.udp_socket
-                                .send_to(packet, client_query.client_addr.unwrap());
+                        let udp_socket_idx = client_query.udp_socket_idx.unwrap();
+                        let client_addr = client_query.client_addr.unwrap();
+                        let rrl_action = self.rrl.check(client_addr.ip(),
+                                                        &client_query.normalized_question.qname,
+                                                        packet);
+                        let response = if rrl_action == RrlAction::Drop {
+                            debug!("Response dropped by RRL");
+                            continue;
+                        } else if rrl_action == RrlAction::Slip ||
+                                  packet.len() >
+                                  client_query.normalized_question.payload_size as usize {
+                            build_tc_packet(&client_query.normalized_question).unwrap()
                         } else {
-                            let _ = self.udp_socket
-                                .send_to(packet, client_query.client_addr.unwrap());
+                            packet.to_vec()
                         };
+                        self.varz.client_response(client_query.normalized_question.qtype,
+                                                  &response);
+                        self.query_log(client_query, &response, cache_status);
+                        match udp_responses.iter().position(|&(idx, _)| idx == udp_socket_idx) {
+                            None => udp_responses.push((udp_socket_idx,
+                                                        vec![(response, client_addr)])),
+                            Some(i) => udp_responses[i].1.push((response, client_addr)),
+                        }
                     }
                 }
-                ClientQueryProtocol::TCP => {
+                ClientQueryProtocol::TCP | ClientQueryProtocol::TLS |
+                ClientQueryProtocol::HTTPS => {
                     let resolver_response = ResolverResponse {
                         response: packet.to_vec(),
                         client_tok: client_query.client_tok.unwrap(),
                         dnssec: client_query.normalized_question.dnssec,
+                        ttl: ttl,
                     };
                     let tcpclient_tx = client_query.tcpclient_tx.clone().unwrap();
                     let _ = tcpclient_tx.send(resolver_response);
+                    self.query_log(client_query, packet, cache_status);
                 }
+                ClientQueryProtocol::Refresh => {}
             }
         }
+        for (udp_socket_idx, responses) in udp_responses {
+            let socket_fd = self.udp_sockets[udp_socket_idx].as_raw_fd();
+            let _ = udp_batch::send_batch(socket_fd, &responses);
+        }
         self.mio_timers.cancel_timeout(&active_query.timeout);
     }
 
-    fn complete_active_query(&mut self, packet: &mut [u8], normalized_question: NormalizedQuestion, client_addr: SocketAddr, local_port: u16, ttl: u32) {
+    fn complete_active_query(&mut self, packet: &mut [u8], normalized_question: NormalizedQuestion, client_addr: SocketAddr, local_port: Option<u16>, ttl: u32) {
         let normalized_question_key = normalized_question.key();
-        self.dispatch_active_query(packet, &normalized_question_key, client_addr, local_port);
+        self.dispatch_active_query(packet, &normalized_question_key, client_addr, local_port, ttl);
         if let Some(active_query) = self.pending_queries.map.remove(&normalized_question_key) {
             self.waiting_clients_count -= active_query.client_queries.len();
         }
         if rcode(packet) == DNS_RCODE_SERVFAIL {
             match self.cache.get(&normalized_question_key) {
                 None => {
-                    self.cache.insert(normalized_question_key, packet.to_owned(), FAILURE_TTL);
+                    self.cache.insert(normalized_question_key,
+                                      packet.to_owned(),
+                                      self.config.failure_ttl);
                 }
-                Some(cache_entry) => {
-                    self.cache.insert(normalized_question_key, cache_entry.packet, FAILURE_TTL);
+                Some(mut cache_entry) => {
+                    let _ = set_ttl(&mut cache_entry.packet, self.config.failure_ttl);
+                    self.cache.insert(normalized_question_key,
+                                      cache_entry.packet,
+                                      self.config.failure_ttl);
                 }
             }
         } else {
@@ -222,10 +417,17 @@ impl Resolver {
         self.varz.cache_evicted.set(cache_stats.evicted as f64);
     }
 
-    fn handle_upstream_response(&mut self, packet: &mut [u8], client_addr: SocketAddr, local_port: u16) {
+    fn handle_upstream_response(&mut self,
+                                packet: &mut [u8],
+                                client_addr: SocketAddr,
+                                local_port: Option<u16>,
+                                upstream_server_idx: Option<usize>) {
         if packet.len() < DNS_QUERY_MIN_SIZE {
-            info!("Short response without a query, using UDP");
+            info!("Short response without a query");
             self.varz.upstream_errors.inc();
+            if let Some(upstream_server_idx) = upstream_server_idx {
+                self.upstream_server_failed(upstream_server_idx);
+            }
             return;
         }
         let normalized_question = match normalize(packet, false) {
@@ -235,27 +437,42 @@ impl Resolver {
             }
             Ok(normalized_question) => normalized_question,
         };
+        if let Some(local_port) = local_port {
+            if tc(packet) {
+                debug!("Truncated response received, retrying using TCP");
+                self.varz.upstream_truncated.inc();
+                self.upstream_tcp_fallback(packet, &normalized_question, client_addr, local_port);
+                return;
+            }
+        }
         let ttl = match min_ttl(packet,
                                 self.config.min_ttl,
                                 self.config.max_ttl,
-                                FAILURE_TTL) {
+                                self.config.negative_min_ttl,
+                                self.config.negative_max_ttl,
+                                self.config.failure_ttl) {
             Err(e) => {
                 info!("Unexpected answers in a response ({}): {}",
                       normalized_question,
                       e);
                 self.varz.upstream_errors.inc();
+                if let Some(upstream_server_idx) = upstream_server_idx {
+                    self.upstream_server_failed(upstream_server_idx);
+                }
                 return;
             }
             Ok(ttl) => {
                 if rcode(packet) == DNS_RCODE_SERVFAIL {
-                    let _ = set_ttl(packet, FAILURE_TTL);
-                    FAILURE_TTL
-                } else if ttl < self.config.min_ttl {
+                    let _ = set_ttl(packet, self.config.failure_ttl);
+                    self.config.failure_ttl
+                } else {
                     if self.decrement_ttl {
-                        let _ = set_ttl(packet, self.config.min_ttl);
+                        if is_negative(packet) {
+                            let _ = set_ttl(packet, ttl);
+                        } else {
+                            let _ = clamp_ttls(packet, self.config.min_ttl, self.config.max_ttl);
+                        }
                     }
-                    self.config.min_ttl
-                } else {
                     ttl
                 }
             }
@@ -269,48 +486,347 @@ impl Resolver {
             debug!("Not readable");
             return;
         }
+        let (socket_fd, local_port) = {
+            let ext_udp_socket_tuple = &self.ext_udp_sockets.tuples[usize::from(token)];
+            (ext_udp_socket_tuple.ext_udp_socket.as_raw_fd(), ext_udp_socket_tuple.local_port)
+        };
+        let mut recv_batch = mem::replace(&mut self.recv_batch, RecvBatch::new(0, 0));
         loop {
-            let mut packet = [0u8; DNS_MAX_SIZE];
-            let (count, client_addr, local_port) =
-            {
-                let ext_udp_socket_tuple = &self.ext_udp_socket_tuples[usize::from(token)];
-                let ext_udp_socket = &ext_udp_socket_tuple.ext_udp_socket;
-                match ext_udp_socket.recv_from(&mut packet).expect("UDP socket error") {
-                    None => break,
-                    Some((count, client_addr)) => (count, client_addr, ext_udp_socket_tuple.local_port)
+            let count = match udp_batch::recv_batch(socket_fd, &mut recv_batch, true) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => panic!("UDP socket error: {}", e),
+            };
+            for i in 0..count {
+                let (packet, client_addr) = match recv_batch.get_mut(i) {
+                    (packet, Some(client_addr)) => (packet, client_addr),
+                    _ => continue,
+                };
+                let upstream_server_idx = self.upstream_servers
+                    .iter()
+                    .position(|upstream_server| {
+                        upstream_server.tls_server_name.is_none() &&
+                        upstream_server.socket_addr == client_addr
+                    });
+                if packet.len() < DNS_HEADER_SIZE {
+                    info!("Short response without a header, using UDP");
+                    self.varz.upstream_errors.inc();
+                    if let Some(upstream_server_idx) = upstream_server_idx {
+                        self.upstream_server_failed(upstream_server_idx);
+                    }
+                    continue;
                 }
+                if let Some(upstream_server_idx) = upstream_server_idx {
+                    self.upstream_response_received(upstream_server_idx);
+                }
+                self.handle_upstream_response(packet,
+                                              client_addr,
+                                              Some(local_port),
+                                              upstream_server_idx)
+            }
+        }
+        self.recv_batch = recv_batch;
+    }
+
+    fn upstream_response_received(&mut self, idx: usize) {
+        if idx < self.upstream_servers.len() {
+            if !self.upstream_servers_live.iter().any(|&x| x == idx) {
+                self.upstream_servers[idx].failures = 0;
+                self.upstream_servers[idx].offline = false;
+                self.upstream_servers[idx].update_online_varz(&self.varz);
+                self.upstream_servers_live.push(idx);
+                self.upstream_servers_live.sort();
+                info!("{} came back online",
+                      self.upstream_servers[idx].remote_addr);
+            } else if self.upstream_servers[idx].failures > 0 {
+                self.upstream_servers[idx].failures -= 1;
+                debug!("Failures count for server {} decreased to {}",
+                       idx,
+                       self.upstream_servers[idx].failures);
+            }
+        }
+    }
+
+    fn upstream_server_failed(&mut self, upstream_server_idx: usize) {
+        if let Some(upstream_server) = self.upstream_servers.get_mut(upstream_server_idx) {
+            upstream_server.failures += 1;
+            self.varz
+                .upstream_server_failures
+                .with_label_values(&[upstream_server.remote_addr.as_str()])
+                .inc();
+        }
+    }
+
+    fn client_latency_observe(&self, client_query: &ClientQuery) {
+        if let ClientQueryProtocol::Refresh = client_query.proto {
+            return;
+        }
+        self.varz
+            .client_latency
+            .with_label_values(&[client_query.proto.name(), "miss"])
+            .observe(duration_secs(client_query.ts.elapsed()));
+    }
+
+    fn query_log(&self, client_query: &ClientQuery, packet: &[u8], cache_status: CacheStatus) {
+        if let Some(client_addr) = client_query.client_addr {
+            self.query_log.log(client_addr.ip(),
+                               client_query.proto,
+                               &client_query.normalized_question,
+                               packet,
+                               cache_status,
+                               client_query.ts);
+        }
+    }
+
+    fn upstream_tls_ready(&mut self, connection_idx: usize, events: Ready) {
+        let (upstream_server_idx, socket_addr, responses) =
+            match self.upstream_tls_pool.ready(&self.mio_poll, connection_idx, events) {
+                None => return,
+                Some(res) => res,
             };
-            if count < DNS_HEADER_SIZE {
-                info!("Short response without a header, using UDP");
+        for mut packet in responses {
+            if packet.len() < DNS_HEADER_SIZE {
+                info!("Short response without a header, using TLS");
                 self.varz.upstream_errors.inc();
+                self.upstream_server_failed(upstream_server_idx);
                 continue;
             }
-            if let Some(idx) = self.upstream_servers
-                .iter()
-                .position(|upstream_server| upstream_server.socket_addr == client_addr) {
-                if !self.upstream_servers_live.iter().any(|&x| x == idx) {
-                    self.upstream_servers[idx].failures = 0;
-                    self.upstream_servers[idx].offline = false;
-                    self.upstream_servers_live.push(idx);
-                    self.upstream_servers_live.sort();
-                    info!("{} came back online",
-                          self.upstream_servers[idx].remote_addr);
-                } else if self.upstream_servers[idx].failures > 0 {
-                    self.upstream_servers[idx].failures -= 1;
-                    debug!("Failures count for server {} decreased to {}",
-                           idx,
-                           self.upstream_servers[idx].failures);
+            self.upstream_response_received(upstream_server_idx);
+            self.handle_upstream_response(&mut packet,
+                                          socket_addr,
+                                          None,
+                                          Some(upstream_server_idx))
+        }
+    }
+
+    fn upstream_tcp_fallback(&mut self, packet: &[u8], normalized_question: &NormalizedQuestion, client_addr: SocketAddr, local_port: u16) {
+        let normalized_question_key = normalized_question.key();
+        {
+            let active_query = match self.pending_queries.map.get(&normalized_question_key) {
+                None => {
+                    debug!("No clients waiting for this truncated response");
+                    return;
+                }
+                Some(active_query) => active_query,
+            };
+            if self.verify_active_query(&active_query, packet, client_addr, Some(local_port)).is_err() {
+                debug!("Received truncated response is not valid for the query originally sent");
+                return;
+            }
+            if active_query.upstream_tcp_idx.is_some() {
+                debug!("A TCP query is already in flight for this question");
+                return;
+            }
+        }
+        let (query_packet, normalized_question_minimal) =
+            match build_query_packet(normalized_question, false) {
+                Err(e) => {
+                    info!("Unable to build a TCP query: {}", e);
+                    return;
+                }
+                Ok(res) => res,
+            };
+        let upstream_tcp_query = match UpstreamTcpQuery::new(normalized_question_key.clone(),
+                                                             client_addr,
+                                                             &query_packet) {
+            Err(e) => {
+                info!("Unable to connect to {:?} using TCP: {}", client_addr, e);
+                self.upstream_tcp_failed(&normalized_question_key);
+                return;
+            }
+            Ok(upstream_tcp_query) => upstream_tcp_query,
+        };
+        let upstream_tcp_idx = match self.upstream_tcp_queries.insert(upstream_tcp_query) {
+            Err(_) => {
+                info!("Too many upstream TCP queries in flight");
+                self.upstream_tcp_failed(&normalized_question_key);
+                return;
+            }
+            Ok(upstream_tcp_idx) => upstream_tcp_idx,
+        };
+        {
+            let upstream_tcp_query = &mut self.upstream_tcp_queries[upstream_tcp_idx];
+            self.mio_poll
+                .register(&upstream_tcp_query.tcp_stream,
+                          Token(UPSTREAM_TCP_TOK_BASE + upstream_tcp_idx),
+                          upstream_tcp_query.interest(),
+                          PollOpt::edge() | PollOpt::oneshot())
+                .expect("Unable to register an upstream TCP connection");
+            if let Ok(timeout) = self.mio_timers
+                .set_timeout(Duration::from_millis(UPSTREAM_TCP_TIMEOUT_MS),
+                             TimeoutToken::UpstreamTcp(upstream_tcp_idx)) {
+                upstream_tcp_query.timeout = Some(timeout);
+            }
+        }
+        if let Some(active_query) = self.pending_queries.map.get_mut(&normalized_question_key) {
+            active_query.normalized_question_minimal = normalized_question_minimal;
+            active_query.upstream_tcp_idx = Some(upstream_tcp_idx);
+        }
+    }
+
+    fn upstream_tcp_ready(&mut self, upstream_tcp_idx: usize, events: Ready) {
+        let res = {
+            let upstream_tcp_query = match self.upstream_tcp_queries.get_mut(upstream_tcp_idx) {
+                None => {
+                    debug!("Event for a nonexistent upstream TCP query");
+                    return;
+                }
+                Some(upstream_tcp_query) => upstream_tcp_query,
+            };
+            if events.is_error() {
+                Err(io::Error::new(io::ErrorKind::Other, "Upstream TCP connection error"))
+            } else {
+                let mut res = Ok(None);
+                if events.is_writable() {
+                    res = upstream_tcp_query.flush().map(|_| None);
                 }
+                if res.is_ok() && (events.is_readable() || events.is_hup()) {
+                    res = upstream_tcp_query.read_response();
+                }
+                res
+            }
+        };
+        match res {
+            Err(e) => {
+                info!("Upstream TCP query failed: {}", e);
+                let normalized_question_key =
+                    self.upstream_tcp_queries[upstream_tcp_idx].normalized_question_key.clone();
+                self.upstream_tcp_close(upstream_tcp_idx);
+                self.upstream_tcp_failed(&normalized_question_key);
+            }
+            Ok(None) => {
+                let upstream_tcp_query = &self.upstream_tcp_queries[upstream_tcp_idx];
+                self.mio_poll
+                    .reregister(&upstream_tcp_query.tcp_stream,
+                                Token(UPSTREAM_TCP_TOK_BASE + upstream_tcp_idx),
+                                upstream_tcp_query.interest(),
+                                PollOpt::edge() | PollOpt::oneshot())
+                    .expect("Unable to reregister an upstream TCP connection");
+            }
+            Ok(Some(mut packet)) => {
+                let (normalized_question_key, socket_addr) = {
+                    let upstream_tcp_query = &self.upstream_tcp_queries[upstream_tcp_idx];
+                    (upstream_tcp_query.normalized_question_key.clone(),
+                     upstream_tcp_query.socket_addr)
+                };
+                self.upstream_tcp_close(upstream_tcp_idx);
+                if packet.len() < DNS_HEADER_SIZE {
+                    info!("Short response without a header, using TCP");
+                    self.upstream_tcp_failed(&normalized_question_key);
+                    return;
+                }
+                let upstream_server_idx = self.pending_queries
+                    .map
+                    .get(&normalized_question_key)
+                    .map(|active_query| active_query.upstream_server_idx);
+                self.handle_upstream_response(&mut packet, socket_addr, None, upstream_server_idx)
+            }
+        }
+    }
+
+    fn upstream_tcp_close(&mut self, upstream_tcp_idx: usize) {
+        let upstream_tcp_query = match self.upstream_tcp_queries.remove(upstream_tcp_idx) {
+            None => return,
+            Some(upstream_tcp_query) => upstream_tcp_query,
+        };
+        if let Some(ref timeout) = upstream_tcp_query.timeout {
+            self.mio_timers.cancel_timeout(timeout);
+        }
+        let _ = self.mio_poll.deregister(&upstream_tcp_query.tcp_stream);
+        if let Some(active_query) = self.pending_queries
+            .map
+            .get_mut(&upstream_tcp_query.normalized_question_key) {
+            if active_query.upstream_tcp_idx == Some(upstream_tcp_idx) {
+                active_query.upstream_tcp_idx = None;
             }
-            let packet = &mut packet[..count];
-            self.handle_upstream_response(packet, client_addr, local_port)
         }
     }
 
+    fn upstream_tcp_failed(&mut self, normalized_question_key: &NormalizedQuestionKey) {
+        self.varz.upstream_errors.inc();
+        let upstream_server_idx = match self.pending_queries.map.get(normalized_question_key) {
+            None => return,
+            Some(active_query) => {
+                self.mio_timers.cancel_timeout(&active_query.timeout);
+                active_query.upstream_server_idx
+            }
+        };
+        self.upstream_server_failed(upstream_server_idx);
+        self.fail_active_query(normalized_question_key.clone(), false);
+    }
+
+    fn upstream_tls_closed(&mut self) {
+        loop {
+            let closed = self.upstream_tls_pool.take_closed();
+            if closed.is_empty() {
+                return;
+            }
+            let keys: Vec<NormalizedQuestionKey> = self.pending_queries
+                .map
+                .iter()
+                .filter(|&(_, active_query)| {
+                    active_query.upstream_tls_id
+                        .map_or(false, |connection_id| closed.contains(&connection_id))
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in keys {
+                debug!("Resending a query after its TLS connection was closed");
+                self.resend_active_query(&key);
+            }
+        }
+    }
+
+    fn resend_active_query(&mut self, normalized_question_key: &NormalizedQuestionKey) {
+        let active_query = match self.pending_queries.map.get_mut(normalized_question_key) {
+            None => return,
+            Some(active_query) => active_query,
+        };
+        active_query.upstream_tls_id = None;
+        let normalized_question = match active_query.client_queries.first() {
+            None => return,
+            Some(client_query) => client_query.normalized_question.clone(),
+        };
+        let (query_packet,
+             normalized_question_minimal,
+             upstream_server_idx,
+             ext_udp_socket_tuple) =
+            match normalized_question.new_active_query(&self.upstream_servers,
+                                                       &self.upstream_servers_live,
+                                                       &self.ext_udp_sockets,
+                                                       true,
+                                                       self.failover) {
+                Err(_) => return,
+                Ok(res) => res,
+            };
+        let upstream_server = &self.upstream_servers[upstream_server_idx];
+        active_query.normalized_question_minimal = normalized_question_minimal;
+        active_query.socket_addr = upstream_server.socket_addr;
+        active_query.local_port = ext_udp_socket_tuple.local_port;
+        active_query.upstream_server_idx = upstream_server_idx;
+        active_query.sent_ts = Instant::now();
+        let res = upstream_server.send_query(upstream_server_idx,
+                                             &query_packet,
+                                             ext_udp_socket_tuple,
+                                             &mut self.upstream_tls_pool,
+                                             &self.mio_poll);
+        upstream_server.update_sent_varz(&self.varz, &res);
+        active_query.upstream_tls_id = res.unwrap_or(None);
+    }
+
     fn notify(&mut self, client_query: ClientQuery) {
+        if let ClientQueryProtocol::Refresh = client_query.proto {
+            if self.drained_tx.is_some() {
+                debug!("Ignoring a refresh while shutting down");
+                return;
+            }
+        }
         let normalized_question = &client_query.normalized_question;
         let key = normalized_question.key();
-        if self.waiting_clients_count > MAX_WAITING_CLIENTS {
+        if self.waiting_clients_count >
+           self.config.max_active_queries * MAX_WAITING_CLIENTS_PER_QUERY {
             info!("Too many waiting clients, dropping the first slot");
             let key = match self.pending_queries.map.keys().next() {
                 None => return,
@@ -325,24 +841,31 @@ impl Resolver {
         let mut create_active_query = true;
         if let Some(active_query) = self.pending_queries.map.get_mut(&key) {
             create_active_query = false;
-            if active_query.client_queries.len() < MAX_CLIENTS_WAITING_FOR_QUERY {
+            if let ClientQueryProtocol::Refresh = client_query.proto {
+                debug!("Refresh already in flight");
+            } else if active_query.client_queries.len() < MAX_CLIENTS_WAITING_FOR_QUERY {
                 active_query.client_queries.push(client_query.clone());
                 self.waiting_clients_count += 1;
             } else {
                 info!("More than {} clients waiting for a response to the same query",
                       MAX_CLIENTS_WAITING_FOR_QUERY);
             }
-            let obsolete = active_query.ts.elapsed() >
+            let obsolete = active_query.upstream_tcp_idx.is_none() &&
+                           active_query.ts.elapsed() >
                            Duration::from_millis(active_query.delay as u64);
             if obsolete {
                 let mut new_server_went_offline = false;
-                {
-                    let mut previous_upstream_server =
-                        &mut self.upstream_servers[active_query.upstream_server_idx];
+                if let Some(previous_upstream_server) =
+                    self.upstream_servers.get_mut(active_query.upstream_server_idx) {
+                    self.varz
+                        .upstream_server_timeout
+                        .with_label_values(&[previous_upstream_server.remote_addr.as_str()])
+                        .inc();
                     if previous_upstream_server.failures >= self.upstream_max_failures {
                         if !previous_upstream_server.offline {
                             warn!("Putting {:?} offline", previous_upstream_server.socket_addr);
                             previous_upstream_server.offline = true;
+                            previous_upstream_server.update_online_varz(&self.varz);
                         }
                         new_server_went_offline = true;
                     } else {
@@ -357,6 +880,8 @@ impl Resolver {
                                previous_upstream_server.failures,
                                self.upstream_max_failures);
                     }
+                } else {
+                    active_query.delay *= 2;
                 }
                 if new_server_went_offline && !self.upstream_servers_live.is_empty() {
                     debug!("Live upstream servers before removal of the dead one: {:?}",
@@ -372,7 +897,7 @@ impl Resolver {
                     debug!("Live upstream servers after removal of the dead one: {:?}",
                            self.upstream_servers_live);
                 }
-                if active_query.delay > UPSTREAM_MAX_TIMEOUT_MS {
+                if active_query.delay > self.config.upstream_max_timeout_ms {
                     debug!("Timeout deadline reached while waiting for a response from resolver");
                     return;
                 }
@@ -382,7 +907,7 @@ impl Resolver {
                      ext_udp_socket_tuple) =
                     match normalized_question.new_active_query(&self.upstream_servers,
                                                                &self.upstream_servers_live,
-                                                               &self.ext_udp_socket_tuples,
+                                                               &self.ext_udp_sockets,
                                                                true,
                                                                self.failover) {
                         Err(_) => return,
@@ -392,9 +917,15 @@ impl Resolver {
                 active_query.normalized_question_minimal = normalized_question_minimal;
                 active_query.socket_addr = upstream_server.socket_addr;
                 active_query.local_port = ext_udp_socket_tuple.local_port;
-                ext_udp_socket_tuple.ext_udp_socket
-                    .send_to(&query_packet, &upstream_server.socket_addr)
-                    .unwrap();
+                active_query.upstream_server_idx = upstream_server_idx;
+                active_query.sent_ts = Instant::now();
+                let res = upstream_server.send_query(upstream_server_idx,
+                                                     &query_packet,
+                                                     ext_udp_socket_tuple,
+                                                     &mut self.upstream_tls_pool,
+                                                     &self.mio_poll);
+                upstream_server.update_sent_varz(&self.varz, &res);
+                active_query.upstream_tls_id = res.unwrap_or(None);
             }
             debug_assert_eq!(create_active_query, false);
         }
@@ -405,7 +936,7 @@ impl Resolver {
                  ext_udp_socket_tuple) =
                 match normalized_question.new_active_query(&self.upstream_servers,
                                                            &self.upstream_servers_live,
-                                                           &self.ext_udp_socket_tuples,
+                                                           &self.ext_udp_sockets,
                                                            false,
                                                            self.failover) {
                     Err(_) => return,
@@ -418,33 +949,80 @@ impl Resolver {
                 Err(_) => return,
                 Ok(timeout) => timeout,
             };
-            let active_query = ActiveQuery {
+            let mut active_query = ActiveQuery {
                 normalized_question_minimal: normalized_question_minimal,
                 socket_addr: upstream_server.socket_addr,
                 local_port: ext_udp_socket_tuple.local_port,
                 client_queries: vec![client_query.clone()],
                 ts: Instant::now(),
-                delay: UPSTREAM_INITIAL_TIMEOUT_MS,
+                sent_ts: Instant::now(),
+                delay: self.config.upstream_initial_timeout_ms,
                 upstream_server_idx: upstream_server_idx,
+                upstream_tcp_idx: None,
+                upstream_tls_id: None,
                 timeout: timeout,
             };
+            let res = upstream_server.send_query(upstream_server_idx,
+                                                 &query_packet,
+                                                 ext_udp_socket_tuple,
+                                                 &mut self.upstream_tls_pool,
+                                                 &self.mio_poll);
+            upstream_server.update_sent_varz(&self.varz, &res);
+            active_query.upstream_tls_id = res.unwrap_or(None);
             self.pending_queries.map.insert(key, active_query);
             self.waiting_clients_count += 1;
-            ext_udp_socket_tuple.ext_udp_socket
-                .send_to(&query_packet, &upstream_server.socket_addr)
-                .unwrap();
         }
     }
 }
 
 impl Resolver {
     fn timeout_question(&mut self, normalized_question_key: NormalizedQuestionKey) {
+        self.fail_active_query(normalized_question_key, true)
+    }
+
+    fn fail_active_query(&mut self,
+                         normalized_question_key: NormalizedQuestionKey,
+                         timed_out: bool) {
         if let Some(active_query) = self.pending_queries.map.remove(&normalized_question_key) {
+            if timed_out {
+                self.varz.upstream_timeout.inc();
+            }
+            let upstream_server = self.upstream_servers.get(active_query.upstream_server_idx);
+            if let Some(upstream_server) = upstream_server {
+                if timed_out {
+                    self.varz
+                        .upstream_server_timeout
+                        .with_label_values(&[upstream_server.remote_addr.as_str()])
+                        .inc();
+                }
+            }
             let cache_entry = self.cache.get(&normalized_question_key);
-            let outdated_packet = if let Some(cache_entry) = cache_entry {
-                Some(cache_entry.packet)
+            let outdated_packet = match cache_entry {
+                Some(mut cache_entry) => {
+                    if self.config.serve_stale_max == 0 ||
+                       self.cache.serve_stale(&mut cache_entry) {
+                        // Without a serve-stale window, any cached version is still
+                        // better than a SERVFAIL, but with a TTL as short as a stale one
+                        let _ = set_ttl(&mut cache_entry.packet, SERVE_STALE_TTL);
+                        Some(cache_entry.packet)
+                    } else {
+                        debug!("Cached response is too old to be served");
+                        None
+                    }
+                }
+                None => None,
+            };
+            let ttl = if outdated_packet.is_some() {
+                SERVE_STALE_TTL
             } else {
-                None
+                0
+            };
+            let cache_status = if outdated_packet.is_some() {
+                CacheStatus::Stale
+            } else {
+                CacheStatus::Miss(upstream_server.map(|upstream_server| {
+                    upstream_server.remote_addr.as_str()
+                }))
             };
             let client_queries = &active_query.client_queries;
             for client_query in client_queries {
@@ -457,134 +1035,278 @@ impl Resolver {
                     build_servfail_packet(&client_query.normalized_question).unwrap()
                 };
                 set_tid(&mut packet, client_query.normalized_question.tid);
-                self.varz.upstream_timeout.inc();
+                self.client_latency_observe(client_query);
                 match client_query.proto {
                     ClientQueryProtocol::UDP => {
                         if client_query.ts.elapsed() < Duration::from_millis(UPSTREAM_TIMEOUT_MS) {
-                            if packet.len() >
-                               client_query.normalized_question.payload_size as usize {
+                            let udp_socket =
+                                &self.udp_sockets[client_query.udp_socket_idx.unwrap()];
+                            let client_addr = client_query.client_addr.unwrap();
+                            let rrl_action =
+                                self.rrl.check(client_addr.ip(),
+                                               &client_query.normalized_question.qname,
+                                               &packet);
+                            if rrl_action == RrlAction::Drop {
+                                debug!("Response dropped by RRL");
+                            } else if rrl_action == RrlAction::Slip ||
+                                      packet.len() >
+                                      client_query.normalized_question.payload_size as usize {
                                 let packet = build_tc_packet(&client_query.normalized_question)
                                     .unwrap();
-                                let _ = self.udp_socket
-                                    .send_to(&packet, client_query.client_addr.unwrap());
+                                let _ = udp_socket.send_to(&packet, client_addr);
+                                self.varz
+                                    .client_response(client_query.normalized_question.qtype,
+                                                     &packet);
+                                self.query_log(client_query, &packet, cache_status);
                             } else {
-                                let _ = self.udp_socket
-                                    .send_to(&packet, client_query.client_addr.unwrap());
+                                let _ = udp_socket.send_to(&packet, client_addr);
+                                self.varz
+                                    .client_response(client_query.normalized_question.qtype,
+                                                     &packet);
+                                self.query_log(client_query, &packet, cache_status);
                             };
                         }
                     }
-                    ClientQueryProtocol::TCP => {
+                    ClientQueryProtocol::TCP | ClientQueryProtocol::TLS |
+                    ClientQueryProtocol::HTTPS => {
                         let resolver_response = ResolverResponse {
                             response: packet.to_vec(),
                             client_tok: client_query.client_tok.unwrap(),
                             dnssec: client_query.normalized_question.dnssec,
+                            ttl: ttl,
                         };
                         let tcpclient_tx = client_query.tcpclient_tx.clone().unwrap();
                         let _ = tcpclient_tx.send(resolver_response);
+                        self.query_log(client_query, &packet, cache_status);
                     }
+                    ClientQueryProtocol::Refresh => {}
                 }
             }
             self.waiting_clients_count -= active_query.client_queries.len();
         }
     }
 
+    fn timeout_upstream_tcp(&mut self, upstream_tcp_idx: usize) {
+        let normalized_question_key = match self.upstream_tcp_queries.get_mut(upstream_tcp_idx) {
+            None => return,
+            Some(upstream_tcp_query) => {
+                info!("Timeout while waiting for a TCP response from {:?}",
+                      upstream_tcp_query.socket_addr);
+                upstream_tcp_query.timeout = None;
+                upstream_tcp_query.normalized_question_key.clone()
+            }
+        };
+        self.upstream_tcp_close(upstream_tcp_idx);
+        self.upstream_tcp_failed(&normalized_question_key);
+    }
+
+    fn timeout_upstream_tls_idle(&mut self) {
+        self.upstream_tls_pool.close_idle(&self.mio_poll);
+        self.mio_timers
+            .set_timeout(Duration::from_millis(UPSTREAM_TLS_IDLE_TIMEOUT_MS),
+                         TimeoutToken::UpstreamTlsIdle)
+            .expect("Unable to reschedule the upstream TLS idle check");
+    }
+
     fn timeout_health_check(&mut self) {
         if self.upstream_servers_live.is_empty() {
             info!("All resolvers are dead - forcing them back to life");
             for upstream_server in &mut self.upstream_servers {
                 upstream_server.failures = 0;
                 upstream_server.offline = false;
+                upstream_server.update_online_varz(&self.varz);
             }
             self.upstream_servers_live = (0..self.upstream_servers.len()).collect();
         } else {
             let (packet, _normalized_question) = build_health_check_packet().unwrap();
-            let mut rng = rand::thread_rng();
-            let random_token_range = Range::new(0usize, self.ext_udp_socket_tuples.len());
-            for upstream_server in self.upstream_servers
+            for (upstream_server_idx, upstream_server) in self.upstream_servers
                 .iter()
-                .filter(|upstream_server| upstream_server.offline) {
-                let random_token = random_token_range.ind_sample(&mut rng);
-                let ext_udp_socket_tuple = &self.ext_udp_socket_tuples[random_token];
-                match ext_udp_socket_tuple.ext_udp_socket
-                    .send_to(&packet, &upstream_server.socket_addr) {
+                .enumerate()
+                .filter(|&(_, upstream_server)| upstream_server.offline) {
+                let ext_udp_socket_tuple =
+                    match self.ext_udp_sockets.random_for(&upstream_server.socket_addr) {
+                        None => continue,
+                        Some(ext_udp_socket_tuple) => ext_udp_socket_tuple,
+                    };
+                match upstream_server.send_query(upstream_server_idx,
+                                                 &packet,
+                                                 ext_udp_socket_tuple,
+                                                 &mut self.upstream_tls_pool,
+                                                 &self.mio_poll) {
                     Ok(_) => debug!("Health check send to {:?}", upstream_server.socket_addr),
                     Err(e) => warn!("Couldn't send a health check packet: {}", e),
                 };
             }
         }
         self.mio_timers
-            .set_timeout(Duration::from_millis(HEALTH_CHECK_MS),
+            .set_timeout(Duration::from_millis(self.config.health_check_ms),
                          TimeoutToken::HealthCheck)
             .expect("Unable to reschedule the health check");
     }
 
-    pub fn spawn(rpdns_context: &RPDNSContext) -> io::Result<channel::SyncSender<ClientQuery>> {
+    fn command(&mut self, resolver_command: ResolverCommand) {
+        match resolver_command {
+            ResolverCommand::Reload(config) => self.reload(config),
+            ResolverCommand::Shutdown(drained_tx) => {
+                info!("Waiting for {} pending queries", self.pending_queries.map.len());
+                self.drained_tx = Some(drained_tx);
+            }
+        }
+    }
+
+    fn check_drained(&mut self) {
+        if self.drained_tx.is_none() || !self.pending_queries.map.is_empty() {
+            return;
+        }
+        if let Some(drained_tx) = self.drained_tx.take() {
+            let _ = drained_tx.send(());
+        }
+    }
+
+    fn reload(&mut self, config: Config) {
+        if config.upstream_servers != self.config.upstream_servers {
+            self.reload_upstream_servers(&config.upstream_servers);
+        }
+        self.decrement_ttl = config.decrement_ttl;
+        self.failover = config.failover;
+        self.upstream_max_failures = config.upstream_max_failures;
+        self.config = config;
+        info!("Resolver configuration reloaded");
+    }
+
+    fn reload_upstream_servers(&mut self, upstream_addrs: &[UpstreamAddr]) {
+        let mut upstream_servers: Vec<UpstreamServer> = Vec::with_capacity(upstream_addrs.len());
+        for upstream_addr in upstream_addrs {
+            let mut upstream_server = UpstreamServer::new(upstream_addr);
+            let ports_bound = match upstream_server.socket_addr {
+                SocketAddr::V4(_) => !self.ext_udp_sockets.v4.is_empty(),
+                SocketAddr::V6(_) => !self.ext_udp_sockets.v6.is_empty(),
+            };
+            if !ports_bound {
+                warn!("Ignoring upstream server [{}]: a restart is required in order to use \
+                       a new address family",
+                      upstream_server.remote_addr);
+                continue;
+            }
+            if let Some(previous_upstream_server) = self.upstream_servers
+                .iter()
+                .find(|x| x.remote_addr == upstream_server.remote_addr) {
+                upstream_server.failures = previous_upstream_server.failures;
+                upstream_server.offline = previous_upstream_server.offline;
+            }
+            upstream_servers.push(upstream_server);
+        }
+        if upstream_servers.is_empty() {
+            warn!("No usable upstream servers in the new configuration - Keeping the current \
+                   ones");
+            return;
+        }
+        let new_idxs: Vec<usize> = self.upstream_servers
+            .iter()
+            .map(|previous_upstream_server| {
+                upstream_servers.iter()
+                    .position(|x| x.remote_addr == previous_upstream_server.remote_addr)
+                    .unwrap_or(usize::MAX)
+            })
+            .collect();
+        for active_query in self.pending_queries.map.values_mut() {
+            active_query.upstream_server_idx = new_idxs.get(active_query.upstream_server_idx)
+                .cloned()
+                .unwrap_or(usize::MAX);
+        }
+        self.upstream_tls_pool.remap(&self.mio_poll, &new_idxs);
+        for (previous_upstream_server, &new_idx) in self.upstream_servers.iter().zip(&new_idxs) {
+            if new_idx == usize::MAX {
+                let _ = self.varz
+                    .upstream_server_online
+                    .remove_label_values(&[previous_upstream_server.remote_addr.as_str()]);
+            }
+        }
+        self.upstream_servers_live = (0..upstream_servers.len())
+            .filter(|&idx| !upstream_servers[idx].offline)
+            .collect();
+        for upstream_server in &upstream_servers {
+            upstream_server.update_online_varz(&self.varz);
+        }
+        info!("Upstream servers: {:?}",
+              upstream_servers.iter().map(|x| x.remote_addr.as_str()).collect::<Vec<_>>());
+        self.upstream_servers = upstream_servers;
+    }
+
+    pub fn spawn(rpdns_context: &RPDNSContext)
+                 -> io::Result<(channel::SyncSender<ClientQuery>,
+                                channel::SyncSender<ResolverCommand>)> {
         let config = &rpdns_context.config;
-        let udp_socket = rpdns_context.udp_socket
-            .try_clone()
-            .expect("Unable to clone the UDP listening socket");
+        let udp_sockets = rpdns_context.udp_sockets
+            .iter()
+            .map(|udp_socket| {
+                udp_socket.try_clone().expect("Unable to clone the UDP listening socket")
+            })
+            .collect();
         let mio_poll = mio::Poll::new().expect("Couldn't instantiate an event loop");
         let mut mio_timers = timer::Builder::default()
-            .num_slots(MAX_ACTIVE_QUERIES / 256)
-            .capacity(MAX_ACTIVE_QUERIES)
+            .num_slots(config.max_active_queries / 256)
+            .capacity(config.max_active_queries)
             .build();
         mio_poll.register(&mio_timers, TIMER_TOK, Ready::readable(), PollOpt::edge())
             .expect("Could not register the timers");
         let (resolver_tx, resolver_rx): (channel::SyncSender<ClientQuery>,
                                          channel::Receiver<ClientQuery>) =
-            channel::sync_channel(MAX_ACTIVE_QUERIES);
+            channel::sync_channel(config.max_active_queries);
         mio_poll.register(&resolver_rx, NOTIFY_TOK, Ready::all(), PollOpt::edge())
             .expect("Could not register the resolver channel");
+        let (command_tx, command_rx): (channel::SyncSender<ResolverCommand>,
+                                       channel::Receiver<ResolverCommand>) =
+            channel::sync_channel(MAX_PENDING_COMMANDS);
+        mio_poll.register(&command_rx, COMMAND_TOK, Ready::all(), PollOpt::edge())
+            .expect("Could not register the resolver command channel");
         let pending_queries = PendingQueries::new();
-        let mut ext_udp_socket_tuples = Vec::new();
+        let upstream_servers: Vec<UpstreamServer> = config.upstream_servers
+            .iter()
+            .map(UpstreamServer::new)
+            .collect();
         let ports = if config.udp_ports > 65535 - 1024 {
             65535 - 1024
         } else {
             config.udp_ports
         };
-        for port in 1024..1024 + ports {
-            if (port + 1) % 1024 == 0 {
-                info!("Binding ports... {}/{}", port, ports)
-            }
-            if let Ok(ext_udp_socket) = mio_socket_udp_bound(port) {
-                mio_poll.register(&ext_udp_socket,
-                              Token(ext_udp_socket_tuples.len()),
-                              Ready::readable(),
-                              PollOpt::edge())
-                    .unwrap();
-                let ext_udp_socket_tuple = ExtUdpSocketTuple {
-                    local_port: port,
-                    ext_udp_socket: ext_udp_socket,
-                };
-                ext_udp_socket_tuples.push(ext_udp_socket_tuple);
-            }
-        }
-        if ext_udp_socket_tuples.is_empty() {
-            panic!("Couldn't bind any ports");
-        }
-        let upstream_servers: Vec<UpstreamServer> = config.upstream_servers
-            .iter()
-            .map(|s| UpstreamServer::new(s).expect("Invalid upstream server address"))
-            .collect();
+        let ext_udp_sockets =
+            ExtUdpSockets::bind(&mio_poll,
+                                ports,
+                                config.udp_buffer_size,
+                                upstream_servers.iter().any(|x| x.socket_addr.is_ipv4()),
+                                upstream_servers.iter().any(|x| x.socket_addr.is_ipv6()));
         let upstream_servers_live: Vec<usize> = (0..config.upstream_servers.len()).collect();
-        mio_timers.set_timeout(Duration::from_millis(HEALTH_CHECK_MS),
+        for upstream_server in &upstream_servers {
+            upstream_server.update_online_varz(&rpdns_context.varz);
+        }
+        mio_timers.set_timeout(Duration::from_millis(config.health_check_ms),
                          TimeoutToken::HealthCheck)
             .expect("Unable to reschedule the health check");
+        mio_timers.set_timeout(Duration::from_millis(UPSTREAM_TLS_IDLE_TIMEOUT_MS),
+                         TimeoutToken::UpstreamTlsIdle)
+            .expect("Unable to schedule the upstream TLS idle check");
         let mut resolver = Resolver {
             mio_poll: mio_poll,
             mio_timers: mio_timers,
             config: rpdns_context.config.clone(),
-            udp_socket: udp_socket,
+            udp_sockets: udp_sockets,
             pending_queries: pending_queries,
-            ext_udp_socket_tuples: ext_udp_socket_tuples,
+            ext_udp_sockets: ext_udp_sockets,
+            recv_batch: RecvBatch::new(MAX_UPSTREAM_UDP_BATCH, DNS_MAX_SIZE),
+            upstream_tcp_queries: Slab::with_capacity(MAX_UPSTREAM_TCP_QUERIES),
+            upstream_tls_pool: UpstreamTlsPool::new(),
             upstream_servers: upstream_servers,
             upstream_servers_live: upstream_servers_live,
             waiting_clients_count: 0,
             cache: rpdns_context.cache.clone(),
+            rrl: rpdns_context.rrl.clone(),
+            query_log: rpdns_context.query_log.clone(),
             varz: rpdns_context.varz.clone(),
             decrement_ttl: config.decrement_ttl,
             failover: config.failover,
             upstream_max_failures: config.upstream_max_failures,
+            drained_tx: None,
         };
         if config.decrement_ttl {
             info!("Resolver mode: TTL will be automatically decremented");
@@ -600,24 +1322,50 @@ impl Resolver {
                     Err(e) => return e,
                     _ => {}
                 }
+                // Queries sent over a closed TLS connection are handled right
+                // after each operation, before a new connection can reuse its index.
                 for event in events.iter() {
                     match event.token() {
                         NOTIFY_TOK => {
                             while let Ok(client_query) = resolver_rx.try_recv() {
-                                resolver.notify(client_query)
+                                resolver.notify(client_query);
+                                resolver.upstream_tls_closed();
                             }
                         }
                         TIMER_TOK => {
                             while let Some(timeout_token) = resolver.mio_timers.poll() {
-                                resolver.timeout(timeout_token)
+                                resolver.timeout(timeout_token);
+                                resolver.upstream_tls_closed();
                             }
                         }
-                        token => resolver.ready(token, event.kind()),
+                        COMMAND_TOK => {
+                            while let Ok(resolver_command) = command_rx.try_recv() {
+                                resolver.command(resolver_command);
+                                resolver.upstream_tls_closed();
+                            }
+                        }
+                        token if usize::from(token) >= UPSTREAM_TLS_TOK_BASE => {
+                            resolver.upstream_tls_ready(usize::from(token) -
+                                                        UPSTREAM_TLS_TOK_BASE,
+                                                        event.kind());
+                            resolver.upstream_tls_closed();
+                        }
+                        token if usize::from(token) >= UPSTREAM_TCP_TOK_BASE => {
+                            resolver.upstream_tcp_ready(usize::from(token) -
+                                                        UPSTREAM_TCP_TOK_BASE,
+                                                        event.kind());
+                            resolver.upstream_tls_closed();
+                        }
+                        token => {
+                            resolver.ready(token, event.kind());
+                            resolver.upstream_tls_closed();
+                        }
                     }
                 }
+                resolver.check_drained();
             }
         });
-        Ok(resolver_tx)
+        Ok((resolver_tx, command_tx))
     }
 }
 
@@ -653,7 +1401,7 @@ impl NormalizedQuestion {
         (&self,
          upstream_servers: &Vec<UpstreamServer>,
          upstream_servers_live: &Vec<usize>,
-         ext_udp_socket_tuples: &'t Vec<ExtUdpSocketTuple>,
+         ext_udp_sockets: &'t ExtUdpSockets,
          is_retry: bool,
          failover: bool)
          -> Result<(Vec<u8>, NormalizedQuestionMinimal, usize, &'t ExtUdpSocketTuple), &'static str> {
@@ -664,24 +1412,25 @@ impl NormalizedQuestion {
                 Err(e) => return Err(e),
                 Ok(upstream_server_idx) => upstream_server_idx,
             };
-        let mut rng = rand::thread_rng();
-        let random_token_range = Range::new(0usize, ext_udp_socket_tuples.len());
-        let random_token = random_token_range.ind_sample(&mut rng);
-        let ext_udp_socket_tuple = &ext_udp_socket_tuples[random_token];
+        let upstream_socket_addr = &upstream_servers[upstream_server_idx].socket_addr;
+        let ext_udp_socket_tuple = match ext_udp_sockets.random_for(upstream_socket_addr) {
+            None => return Err("No local sockets for the address family of the upstream server"),
+            Some(ext_udp_socket_tuple) => ext_udp_socket_tuple,
+        };
         Ok((query_packet, normalized_question_minimal, upstream_server_idx, ext_udp_socket_tuple))
     }
 }
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
-fn socket_udp_set_buffer_size(socket_fd: RawFd) {
-    let _ = setsockopt(socket_fd, sockopt::SndBufForce, &UDP_BUFFER_SIZE);
-    let _ = setsockopt(socket_fd, sockopt::RcvBufForce, &UDP_BUFFER_SIZE);
+fn socket_udp_set_buffer_size(socket_fd: RawFd, buffer_size: usize) {
+    let _ = setsockopt(socket_fd, sockopt::SndBufForce, &buffer_size);
+    let _ = setsockopt(socket_fd, sockopt::RcvBufForce, &buffer_size);
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
-fn socket_udp_set_buffer_size(socket_fd: RawFd) {
-    let _ = setsockopt(socket_fd, sockopt::SndBuf, &UDP_BUFFER_SIZE);
-    let _ = setsockopt(socket_fd, sockopt::RcvBuf, &UDP_BUFFER_SIZE);
+fn socket_udp_set_buffer_size(socket_fd: RawFd, buffer_size: usize) {
+    let _ = setsockopt(socket_fd, sockopt::SndBuf, &buffer_size);
+    let _ = setsockopt(socket_fd, sockopt::RcvBuf, &buffer_size);
 }
 
 fn socket_udp_v4() -> io::Result<RawFd> {
@@ -705,17 +1454,24 @@ fn set_nonblock(sock: RawFd) -> io::Result<()> {
     Ok(())
 }
 
-fn mio_socket_udp_bound(port: u16) -> io::Result<udp::UdpSocket> {
-    let actual = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port));
+fn mio_socket_udp_bound(ip: IpAddr,
+                        port: u16,
+                        buffer_size: usize)
+                        -> io::Result<udp::UdpSocket> {
+    let actual = SocketAddr::new(ip, port);
     let nix_addr = SockAddr::Inet(InetAddr::from_std(&actual));
     let socket_fd = match actual {
         SocketAddr::V4(_) => try!(socket_udp_v4()),
-        SocketAddr::V6(_) => try!(socket_udp_v6()),
+        SocketAddr::V6(_) => {
+            let socket_fd = try!(socket_udp_v6());
+            try!(set_ipv6_only(socket_fd));
+            socket_fd
+        }
     };
     try!(set_nonblock(socket_fd));
     try!(setsockopt(socket_fd, sockopt::ReuseAddr, &true));
     try!(setsockopt(socket_fd, sockopt::ReusePort, &true));
-    socket_udp_set_buffer_size(socket_fd);
+    socket_udp_set_buffer_size(socket_fd, buffer_size);
     try!(bind(socket_fd, &nix_addr));
     let socket: udp::UdpSocket = unsafe { udp::UdpSocket::from_raw_fd(socket_fd) };
     Ok(socket)
@@ -0,0 +1,238 @@
+use acl::client_prefix;
+use clockpro_cache::*;
+use config::Config;
+use dns;
+use siphasher::sip::SipHasher13;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use token_bucket::TokenBucket;
+use varz::Varz;
+
+use super::{RRL_SHARDS, RRL_TABLE_SIZE};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RrlAction {
+    Send,
+    Drop,
+    Slip,
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+struct RrlKey {
+    prefix: IpAddr,
+    qname_lc: Vec<u8>,
+    rcode: u8,
+    negative: bool,
+}
+
+impl RrlKey {
+    // Negative answers are keyed on the owner name of the SOA record found
+    // in the authority section, so that random subdomains of a zone share
+    // a single bucket.
+    fn new(prefix: IpAddr, qname: &[u8], packet: &[u8]) -> RrlKey {
+        let negative = dns::is_negative(packet);
+        let soa_owner = if negative {
+            dns::soa_owner(packet)
+        } else {
+            None
+        };
+        let qname_lc = match soa_owner {
+            None => dns::qname_lc(qname),
+            Some(soa_owner) => dns::qname_lc(&soa_owner),
+        };
+        RrlKey {
+            prefix: prefix,
+            qname_lc: qname_lc,
+            rcode: dns::rcode(packet),
+            negative: negative,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct RrlBucket {
+    token_bucket: TokenBucket,
+    drops: u32,
+}
+
+impl RrlBucket {
+    fn new(rate: u32, now: Instant) -> RrlBucket {
+        RrlBucket {
+            token_bucket: TokenBucket::new(rate, now),
+            drops: 0,
+        }
+    }
+
+    fn take(&mut self, rate: u32, slip: u32, now: Instant) -> RrlAction {
+        if self.token_bucket.take(rate, now) {
+            return RrlAction::Send;
+        }
+        self.drops = self.drops.wrapping_add(1);
+        if slip > 0 && self.drops % slip == 0 {
+            RrlAction::Slip
+        } else {
+            RrlAction::Drop
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Rrl {
+    responses_per_second: u32,
+    slip: u32,
+    log_only: bool,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
+    shards: Arc<Vec<Mutex<ClockProCache<RrlKey, RrlBucket>>>>,
+    varz: Arc<Varz>,
+}
+
+impl Rrl {
+    pub fn new(config: &Config, varz: Arc<Varz>) -> Rrl {
+        let shards = (0..RRL_SHARDS)
+            .map(|_| Mutex::new(ClockProCache::new(RRL_TABLE_SIZE / RRL_SHARDS).unwrap()))
+            .collect();
+        Rrl {
+            responses_per_second: config.rrl_responses_per_second,
+            slip: config.rrl_slip,
+            log_only: config.rrl_log_only,
+            ipv4_prefix_len: config.rrl_ipv4_prefix_len,
+            ipv6_prefix_len: config.rrl_ipv6_prefix_len,
+            shards: Arc::new(shards),
+            varz: varz,
+        }
+    }
+
+    pub fn check(&self, client_ip: IpAddr, qname: &[u8], packet: &[u8]) -> RrlAction {
+        if self.responses_per_second == 0 {
+            return RrlAction::Send;
+        }
+        let prefix = client_prefix(client_ip, self.ipv4_prefix_len, self.ipv6_prefix_len);
+        let key = RrlKey::new(prefix, qname, packet);
+        let mut hs = SipHasher13::new();
+        key.hash(&mut hs);
+        let shard = &self.shards[hs.finish() as usize % RRL_SHARDS];
+        let action = {
+            let mut buckets = shard.lock().unwrap();
+            let now = Instant::now();
+            let action = buckets.get_mut(&key)
+                .map(|bucket| bucket.take(self.responses_per_second, self.slip, now));
+            match action {
+                Some(action) => action,
+                None => {
+                    let mut bucket = RrlBucket::new(self.responses_per_second, now);
+                    let action = bucket.take(self.responses_per_second, self.slip, now);
+                    buckets.insert(key, bucket);
+                    action
+                }
+            }
+        };
+        match action {
+            RrlAction::Send => return RrlAction::Send,
+            RrlAction::Drop => self.varz.rrl_dropped.inc(),
+            RrlAction::Slip => self.varz.rrl_slipped.inc(),
+        }
+        if self.log_only {
+            info!("RRL: response to {} would have been rate limited ({:?})",
+                  client_ip,
+                  action);
+            return RrlAction::Send;
+        }
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    const ZONE: &'static [u8] = b"\x07Example\x03com\x00";
+
+    fn push_u16(packet: &mut Vec<u8>, value: u16) {
+        packet.push((value >> 8) as u8);
+        packet.push(value as u8);
+    }
+
+    fn negative_response(qname: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; dns::DNS_HEADER_SIZE];
+        dns::set_qr(&mut packet, true);
+        dns::set_rcode(&mut packet, dns::DNS_RCODE_NXDOMAIN);
+        dns::set_qdcount(&mut packet, 1);
+        dns::set_nscount(&mut packet, 1);
+        packet.extend_from_slice(qname);
+        packet.push(0);
+        push_u16(&mut packet, dns::DNS_TYPE_A);
+        push_u16(&mut packet, dns::DNS_CLASS_IN);
+        let mut rdata = Vec::new();
+        rdata.extend_from_slice(b"\x02ns\x07example\x03com\x00");
+        rdata.extend_from_slice(b"\x04root\x07example\x03com\x00");
+        rdata.extend_from_slice(&[0u8; 20]);
+        packet.extend_from_slice(ZONE);
+        push_u16(&mut packet, dns::DNS_TYPE_SOA);
+        push_u16(&mut packet, dns::DNS_CLASS_IN);
+        packet.extend_from_slice(&[0, 0, 0x0e, 0x10]);
+        push_u16(&mut packet, rdata.len() as u16);
+        packet.extend_from_slice(&rdata);
+        packet
+    }
+
+    #[test]
+    fn test_bucket_send_drop_slip() {
+        let start = Instant::now();
+        let mut bucket = RrlBucket::new(2, start);
+        assert_eq!(bucket.take(2, 2, start), RrlAction::Send);
+        assert_eq!(bucket.take(2, 2, start), RrlAction::Send);
+        assert_eq!(bucket.take(2, 2, start), RrlAction::Drop);
+        assert_eq!(bucket.take(2, 2, start), RrlAction::Slip);
+        assert_eq!(bucket.take(2, 2, start), RrlAction::Drop);
+        assert_eq!(bucket.take(2, 2, start), RrlAction::Slip);
+        let later = start + Duration::from_millis(1100);
+        assert_eq!(bucket.take(2, 2, later), RrlAction::Send);
+        assert_eq!(bucket.take(2, 2, later), RrlAction::Send);
+        assert_eq!(bucket.take(2, 2, later), RrlAction::Drop);
+    }
+
+    #[test]
+    fn test_bucket_no_slip() {
+        let start = Instant::now();
+        let mut bucket = RrlBucket::new(1, start);
+        assert_eq!(bucket.take(1, 0, start), RrlAction::Send);
+        for _ in 0..4 {
+            assert_eq!(bucket.take(1, 0, start), RrlAction::Drop);
+        }
+    }
+
+    #[test]
+    fn test_negative_key_uses_soa_owner() {
+        let prefix = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0));
+        let qname1 = b"\x03abc\x07example\x03com";
+        let qname2 = b"\x03xyz\x07example\x03com";
+        let key1 = RrlKey::new(prefix, qname1, &negative_response(qname1));
+        let key2 = RrlKey::new(prefix, qname2, &negative_response(qname2));
+        assert!(key1.negative);
+        assert_eq!(key1.rcode, dns::DNS_RCODE_NXDOMAIN);
+        assert_eq!(key1.qname_lc, b"\x07example\x03com".to_vec());
+        assert_eq!(key1, key2);
+    }
+
+    #[test]
+    fn test_positive_key_uses_qname() {
+        let prefix = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0));
+        let qname = b"\x03WWW\x07example\x03com";
+        let mut packet = vec![0u8; dns::DNS_HEADER_SIZE];
+        dns::set_qr(&mut packet, true);
+        dns::set_qdcount(&mut packet, 1);
+        dns::set_ancount(&mut packet, 1);
+        packet.extend_from_slice(qname);
+        packet.push(0);
+        push_u16(&mut packet, dns::DNS_TYPE_A);
+        push_u16(&mut packet, dns::DNS_CLASS_IN);
+        let key = RrlKey::new(prefix, qname, &packet);
+        assert!(!key.negative);
+        assert_eq!(key.qname_lc, b"\x03www\x07example\x03com".to_vec());
+    }
+}
@@ -0,0 +1,36 @@
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigNum, SigSet};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+static TERMINATE: AtomicBool = ATOMIC_BOOL_INIT;
+static RELOAD: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handle_terminate(_: SigNum) {
+    TERMINATE.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_reload(_: SigNum) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+pub fn install() {
+    let sig_action = SigAction::new(SigHandler::Handler(handle_terminate),
+                                    SaFlags::empty(),
+                                    SigSet::empty());
+    for &signum in &[signal::SIGTERM, signal::SIGINT] {
+        unsafe { signal::sigaction(signum, &sig_action) }
+            .expect("Unable to install a signal handler");
+    }
+    let sig_action = SigAction::new(SigHandler::Handler(handle_reload),
+                                    SaFlags::empty(),
+                                    SigSet::empty());
+    unsafe { signal::sigaction(signal::SIGHUP, &sig_action) }
+        .expect("Unable to install a signal handler");
+}
+
+pub fn terminate_requested() -> bool {
+    TERMINATE.load(Ordering::SeqCst)
+}
+
+pub fn reload_requested() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
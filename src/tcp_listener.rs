@@ -1,33 +1,43 @@
 
-use bytes::{Buf, BufMut, ByteBuf};
-use cache::Cache;
+use acl::{Acl, AclAction};
+use cache::{Cache, CacheDecision};
+use client_limiter::ClientLimiter;
 use client_query::*;
 use client::*;
 use dns;
+use heavy_hitters::HeavyHitters;
 use mio;
 use mio::*;
+use net::set_ipv6_only;
+use nix::sys::socket::{bind, listen, setsockopt, sockopt, AddressFamily, SockFlag, SockType,
+                       SockLevel, SockAddr, socket, InetAddr};
+use prometheus::Histogram;
+use query_log::{CacheStatus, QueryLog};
 use rand;
 use rand::distributions::{IndependentSample, Range};
 use resolver::*;
+use rustls::{ServerConfig, ServerSession};
+use signals;
 use siphasher::sip::SipHasher13;
 use slab;
 use std::hash::{Hash, Hasher};
 use std::io;
-use std::io::{Read, Write};
-use std::net::Shutdown;
+use std::net::{self, Shutdown, SocketAddr};
+use std::os::unix::io::FromRawFd;
 use std::sync::Arc;
 use std::sync::mpsc;
 use std::thread;
 use std::time::{Duration, Instant};
 use std::usize;
 use super::RPDNSContext;
-use varz::Varz;
+use varz::{duration_secs, Varz};
 
 type Slab<T> = slab::Slab<T, Token>;
 
-use super::{DNS_QUERY_MIN_SIZE, DNS_QUERY_MAX_SIZE, DNS_MAX_TCP_SIZE, MAX_ACTIVE_QUERIES,
-            MAX_EVENTS_PER_BATCH, MAX_TCP_CLIENTS, MAX_TCP_IDLE_MS, MAX_TCP_HASH_DISTANCE};
+use super::{DNS_QUERY_MIN_SIZE, DNS_MAX_TCP_SIZE, MAIN_LOOP_TICK_MS, MAX_EVENTS_PER_BATCH,
+            MAX_TCP_HASH_DISTANCE, MAX_TCP_PIPELINED_QUERIES, UPSTREAM_TIMEOUT_MS};
 
+const TCP_BACKLOG: usize = 1024;
 const NOTIFY_TOK: Token = Token(usize::MAX - 1);
 const TIMER_TOK: Token = Token(usize::MAX - 2);
 const LISTENER_TOK: Token = Token(usize::MAX - 3);
@@ -38,7 +48,16 @@ pub struct TcpListener {
     resolver_tx: channel::SyncSender<ClientQuery>,
     service_ready_tx: mpsc::SyncSender<u8>,
     cache: Cache,
+    acl: Acl,
+    client_limiter: ClientLimiter,
+    heavy_hitters: HeavyHitters,
+    query_log: QueryLog,
     varz: Arc<Varz>,
+    tls_config: Option<Arc<ServerConfig>>,
+    shutdown_timeout: u64,
+    max_active_queries: usize,
+    max_tcp_clients: usize,
+    max_tcp_idle_ms: u64,
 }
 
 struct TcpListenerHandler {
@@ -49,14 +68,22 @@ struct TcpListenerHandler {
     resolver_tx: channel::SyncSender<ClientQuery>,
     tcpclient_tx: channel::SyncSender<ResolverResponse>,
     clients: Vec<Option<Client>>,
+    acl: Acl,
+    client_limiter: ClientLimiter,
+    heavy_hitters: HeavyHitters,
+    query_log: QueryLog,
     varz: Arc<Varz>,
+    tls_config: Option<Arc<ServerConfig>>,
+    shutting_down: bool,
+    max_tcp_idle_ms: u64,
+    cached_latency: Histogram,
 }
 
 impl TcpListenerHandler {
     fn timeout(&mut self, client_tok: Token) {
         debug!("timeout! {:?}", client_tok);
         let client_idx = usize::from(client_tok) - 2;
-        {
+        let close = {
             let client = &mut match self.clients[client_idx].as_mut() {
                 None => {
                     debug!("Timeout from a nonexistent client received");
@@ -68,60 +95,89 @@ impl TcpListenerHandler {
                 debug!("Timeout from a client in the attic");
                 return;
             }
-            let _ = client.tcp_stream.shutdown(Shutdown::Both);
+            client.timeout = None;
+            let now = Instant::now();
+            for normalized_question in client.take_expired_questions(now) {
+                debug!("No response received in time for a TCP query");
+                if let Ok(packet) = dns::build_servfail_packet(&normalized_question) {
+                    client.queue_response(&packet);
+                    self.varz.client_response(normalized_question.qtype, &packet);
+                }
+            }
+            match client.oldest_deadline() {
+                None => {
+                    let _ = client.flush();
+                    let _ = client.tcp_stream.shutdown(Shutdown::Both);
+                    true
+                }
+                Some(oldest_deadline) => {
+                    debug!("Client still has queries in flight");
+                    client.timeout = self.mio_timers
+                        .set_timeout(oldest_deadline - now, client_tok)
+                        .ok();
+                    client.timeout.is_none()
+                }
+            }
+        };
+        if close {
+            self.reset_connection(client_idx);
+            return;
         }
-        self.reset_connection(client_idx);
+        self.process_queries(client_tok);
+        self.flush_client(client_tok);
     }
 
     fn notify(&mut self, resolver_response: ResolverResponse) {
         let client_tok = resolver_response.client_tok;
         debug!("notify: client_tok: {:?}", client_tok);
         let client_idx = usize::from(client_tok) - 2;
-        let client = &mut match self.clients[client_idx].as_mut() {
-            None => {
-                debug!("Client token not found but notification received");
-                return;
-            }
-            Some(client) => client,
-        };
-        if !client.resolving {
-            debug!("Received a notification from a client that is not resolving yet");
-            return;
-        }
-        let packet = resolver_response.response;
-        let packet_len = packet.len();
-        if packet_len < DNS_QUERY_MIN_SIZE || packet_len > DNS_MAX_TCP_SIZE {
-            info!("Invalid reponse length to send over TCP");
-            let _ = client.tcp_stream.shutdown(Shutdown::Both);
-            return;
-        }
-        let client_normalized_question = match client.normalized_question {
-            None => {
-                debug!("Missing normalized question");
-                let _ = client.tcp_stream.shutdown(Shutdown::Both);
+        {
+            let client = &mut match self.clients[client_idx].as_mut() {
+                None => {
+                    debug!("Client token not found but notification received");
+                    return;
+                }
+                Some(client) => client,
+            };
+            if client.pending_questions.is_empty() {
+                debug!("Received a notification from a client that is not resolving");
                 return;
             }
-            Some(ref normalized_question) => normalized_question,
-        };
-        let normalized_question = match dns::normalize(&packet, false) {
-            Err(_) => {
-                info!("Invalid response to send over TCP");
+            let packet = resolver_response.response;
+            let packet_len = packet.len();
+            if packet_len < DNS_QUERY_MIN_SIZE || packet_len > DNS_MAX_TCP_SIZE {
+                info!("Invalid reponse length to send over TCP");
                 let _ = client.tcp_stream.shutdown(Shutdown::Both);
                 return;
             }
-            Ok(normalized_question) => normalized_question,
-        };
-        if client_normalized_question.dnssec != resolver_response.dnssec ||
-           client_normalized_question.minimal() != normalized_question.minimal() {
-            debug!("Received a response that doesn't match the question (for TCP)");
-            return;
+            let normalized_question = match dns::normalize(&packet, false) {
+                Err(_) => {
+                    info!("Invalid response to send over TCP");
+                    let _ = client.tcp_stream.shutdown(Shutdown::Both);
+                    return;
+                }
+                Ok(normalized_question) => normalized_question,
+            };
+            let normalized_question_minimal = normalized_question.minimal();
+            let question_idx = match client.pending_questions
+                .iter()
+                .map(|pending_question| &pending_question.normalized_question)
+                .position(|client_normalized_question| {
+                    client_normalized_question.dnssec == resolver_response.dnssec &&
+                    client_normalized_question.minimal() == normalized_question_minimal
+                }) {
+                None => {
+                    debug!("Received a response that doesn't match any question (for TCP)");
+                    return;
+                }
+                Some(question_idx) => question_idx,
+            };
+            client.pending_questions.swap_remove(question_idx);
+            client.queue_response(&packet);
+            self.varz.client_response(normalized_question.qtype, &packet);
         }
-        let mut write_buf = ByteBuf::with_capacity(TCP_QUERY_HEADER_SIZE + packet_len);
-        let binlen = [(packet_len >> 8) as u8, packet_len as u8];
-        write_buf.copy_from_slice(&binlen);
-        write_buf.copy_from_slice(&packet);
-        let _ = client.tcp_stream.write(write_buf.bytes());
-        let _ = client.tcp_stream.shutdown(Shutdown::Read);
+        self.process_queries(client_tok);
+        self.flush_client(client_tok);
     }
 
     fn ready(&mut self, token: Token, events: Ready) {
@@ -135,45 +191,37 @@ impl TcpListenerHandler {
             }
             return;
         }
-        if events.is_hup() {
-            debug!("Hup event for {:?}", token);
-            if token_usize >= 2 {
-                let client_idx = token_usize - 2;
-                self.reset_connection(client_idx);
+        if token == LISTENER_TOK {
+            if events.is_readable() {
+                debug!("Read event for {:?}", token);
+                let _ = self.accept();
             }
+            self.mio_poll
+                .reregister(&self.mio_listener,
+                            token,
+                            Ready::readable() | Ready::hup(),
+                            PollOpt::edge() | PollOpt::oneshot())
+                .expect("Cannot reregister an event set for a listener");
             return;
         }
-        if events.is_readable() {
+        let client_tok = token;
+        if events.is_hup() {
+            debug!("Hup event for {:?}", token);
+        }
+        if events.is_readable() || events.is_hup() {
             debug!("Read event for {:?}", token);
-            if token == LISTENER_TOK {
-                let _ = self.accept();
-                self.mio_poll
-                    .reregister(&self.mio_listener,
-                                token,
-                                Ready::readable() | Ready::hup(),
-                                PollOpt::edge() | PollOpt::oneshot())
-                    .expect("Cannot reregister an event set for a listener");
-            } else {
-                let client_tok = token;
-                let client_idx = usize::from(client_tok) - 2;
-                let _ = self.data_received(client_tok);
-                if let Some(ref client) = self.clients[client_idx] {
-                    self.mio_poll
-                        .reregister(&client.tcp_stream,
-                                    client_tok,
-                                    client.interest,
-                                    PollOpt::edge() | PollOpt::oneshot())
-                        .expect("Cannot reregister an event set after a client read");
-                }
-            }
+            let _ = self.data_received(client_tok);
         }
+        if events.is_writable() {
+            debug!("Write event for {:?}", token);
+        }
+        self.flush_client(client_tok);
     }
 }
 
 impl TcpListenerHandler {
     fn accept(&mut self) -> io::Result<()> {
         debug!("accept()");
-        self.varz.client_queries_tcp.inc();
         let tcp_stream = match self.mio_listener.accept() {
             Ok((tcp_stream, _)) => tcp_stream,
             Err(e) => {
@@ -182,6 +230,17 @@ impl TcpListenerHandler {
             }
         };
         let peer_addr = try!(tcp_stream.peer_addr()).ip();
+        let denied = !self.acl.allows(peer_addr);
+        if denied {
+            debug!("Connection from {} denied by the ACL", peer_addr);
+            self.varz.client_queries_denied.inc();
+            if self.acl.action == AclAction::Drop {
+                return Ok(());
+            }
+        }
+        if !self.client_limiter.tcp_connection_opened(peer_addr) {
+            return Ok(());
+        }
         let mut hs = SipHasher13::new();
         peer_addr.hash(&mut hs);
         let h = hs.finish();
@@ -197,20 +256,37 @@ impl TcpListenerHandler {
         }
         if new_slot.is_none() {
             debug!("TCP hash section is full");
-            let mut rng = rand::thread_rng();
-            let random_distance = Range::new(0, MAX_TCP_HASH_DISTANCE);
-            let random_slot = (slot + random_distance.ind_sample(&mut rng)) % self.clients.len();
+            let clients_len = self.clients.len();
+            let same_peer_slot = (0..MAX_TCP_HASH_DISTANCE)
+                .map(|i| (slot + i) % clients_len)
+                .find(|&probed_slot| {
+                    self.clients[probed_slot]
+                        .as_ref()
+                        .map_or(false, |client| client.peer_ip == peer_addr)
+                });
+            let evicted_slot = match same_peer_slot {
+                Some(same_peer_slot) => same_peer_slot,
+                None => {
+                    let mut rng = rand::thread_rng();
+                    let random_distance = Range::new(0, MAX_TCP_HASH_DISTANCE);
+                    (slot + random_distance.ind_sample(&mut rng)) % clients_len
+                }
+            };
             {
-                let client = &self.clients[random_slot]
+                let client = &self.clients[evicted_slot]
                     .as_ref()
-                    .expect("Random TCP slot should not have been free");
+                    .expect("Evicted TCP slot should not have been free");
                 let _ = client.tcp_stream.shutdown(Shutdown::Both);
             }
-            self.reset_connection(random_slot);
-            new_slot = Some(random_slot);
+            self.reset_connection(evicted_slot);
+            new_slot = Some(evicted_slot);
         }
-        let mut client = Client::new(tcp_stream);
-        client.interest.insert(Ready::readable());
+        let tls_session = self.tls_config
+            .as_ref()
+            .map(|tls_config| ServerSession::new(tls_config));
+        let mut client = Client::new(peer_addr, tcp_stream, tls_session);
+        client.refused = denied;
+        client.update_interest();
         let client_idx = new_slot.unwrap();
         self.clients[client_idx] = Some(client);
         let client = &mut self.clients[client_idx].as_mut().unwrap();
@@ -225,7 +301,7 @@ impl TcpListenerHandler {
                       PollOpt::edge() | PollOpt::oneshot())
             .expect("Unable to register a connection");
         if let Ok(timeout) = self.mio_timers
-            .set_timeout(Duration::from_millis(MAX_TCP_IDLE_MS), client_tok) {
+            .set_timeout(Duration::from_millis(self.max_tcp_idle_ms), client_tok) {
             client.timeout = Some(timeout);
         }
         Ok(())
@@ -234,107 +310,181 @@ impl TcpListenerHandler {
     fn data_received(&mut self, client_tok: Token) -> io::Result<()> {
         debug!("data received {:?}", client_tok);
         let client_idx = usize::from(client_tok) - 2;
-        let mut client =
-            &mut self.clients[client_idx].as_mut().expect("Data received from an unwired client");
-        let mut read_buf = &mut client.read_buf;
-        loop {
-            let res = client.tcp_stream.read(unsafe { read_buf.bytes_mut() }).map_non_block();
-            match res {
+        {
+            let client = &mut self.clients[client_idx]
+                .as_mut()
+                .expect("Data received from an unwired client");
+            if let Err(e) = client.fill_read_buf() {
+                error!("{:?} Error while reading socket: {:?}", client_tok, e);
+                let _ = client.tcp_stream.shutdown(Shutdown::Both);
+                return Err(e);
+            }
+        }
+        self.process_queries(client_tok);
+        Ok(())
+    }
+
+    fn process_queries(&mut self, client_tok: Token) {
+        if self.shutting_down {
+            return;
+        }
+        let proto = if self.tls_config.is_some() {
+            ClientQueryProtocol::TLS
+        } else {
+            ClientQueryProtocol::TCP
+        };
+        let client_idx = usize::from(client_tok) - 2;
+        let client = match self.clients[client_idx].as_mut() {
+            None => return,
+            Some(client) => client,
+        };
+        let mut query_received = false;
+        while client.pending_questions.len() < MAX_TCP_PIPELINED_QUERIES {
+            let packet = match client.next_query() {
+                Ok(None) => break,
+                Ok(Some(packet)) => packet,
                 Err(e) => {
-                    error!("{:?} Error while reading socket: {:?}", client_tok, e);
+                    info!("{}", e);
+                    self.varz.client_queries_errors.inc();
+                    let _ = client.tcp_stream.shutdown(Shutdown::Both);
+                    client.read_buf.clear();
                     break;
                 }
-                Ok(None) => {
-                    debug!("Client socket is empty");
+            };
+            query_received = true;
+            let ts = Instant::now();
+            if self.tls_config.is_some() {
+                self.varz.client_queries_tls.inc();
+            } else {
+                self.varz.client_queries_tcp.inc();
+            }
+            let normalized_question = match dns::normalize(&packet, true) {
+                Ok(normalized_question) => normalized_question,
+                Err(e) => {
+                    debug!("Error while parsing the question: {}", e);
+                    self.varz.client_queries_errors.inc();
+                    let _ = client.tcp_stream.shutdown(Shutdown::Both);
+                    client.read_buf.clear();
                     break;
                 }
-                Ok(Some(0)) => {
-                    debug!("Client socket is closed; nothing to read");
-                    break;
+            };
+            self.heavy_hitters.record(client.peer_ip, &normalized_question.qname);
+            // TCP sources can't be spoofed, so rate limited clients get REFUSED
+            if client.refused || !self.client_limiter.query_allowed(client.peer_ip) {
+                debug!("refused");
+                let packet = dns::build_refused_packet(&normalized_question).unwrap();
+                client.queue_response(&packet);
+                self.varz.client_response(normalized_question.qtype, &packet);
+                self.query_log.log(client.peer_ip,
+                                   proto,
+                                   &normalized_question,
+                                   &packet,
+                                   CacheStatus::None,
+                                   ts);
+                continue;
+            }
+            if let Some((mut cache_entry, cache_decision)) =
+                self.cache.lookup(&normalized_question) {
+                self.varz.client_cache_lookup(cache_decision);
+                if cache_decision.needs_refresh() {
+                    let _ = self.resolver_tx.send(ClientQuery::refresh(&normalized_question));
                 }
-                Ok(Some(count)) => {
-                    debug!("Client socket got {} bytes", count);
-                    unsafe { BufMut::advance_mut(read_buf, count) };
-                    let bytes = read_buf.bytes();
-                    let bytes_len = bytes.len();
-                    if bytes_len < TCP_QUERY_HEADER_SIZE {
-                        assert!(client.expected_len.is_none());
-                        continue;
-                    }
-                    if client.expected_len.is_none() {
-                        let expected_len = ((bytes[0] as u16) << 8) | (bytes[1] as u16);
-                        if (expected_len as usize) < DNS_QUERY_MIN_SIZE ||
-                           (expected_len as usize) > DNS_QUERY_MAX_SIZE {
-                            info!("Suspicious query length");
-                            self.varz.client_queries_errors.inc();
-                            let _ = client.tcp_stream.shutdown(Shutdown::Both);
-                            continue;
-                        } else {
-                            debug!("Expected length: {}", expected_len);
-                            client.expected_len = Some(expected_len);
-                        }
-                    }
-                    if let Some(expected_len) = client.expected_len {
-                        if bytes_len > TCP_QUERY_HEADER_SIZE + expected_len as usize {
-                            info!("Large query");
-                            self.varz.client_queries_errors.inc();
-                            let _ = client.tcp_stream.shutdown(Shutdown::Both);
-                            continue;
-                        }
-                        if bytes_len < TCP_QUERY_HEADER_SIZE + expected_len as usize {
-                            info!("Partial query");
-                            continue;
-                        }
-                        assert_eq!(bytes_len, TCP_QUERY_HEADER_SIZE + expected_len as usize);
-                        let packet = &bytes[TCP_QUERY_HEADER_SIZE..];
-                        let normalized_question = match dns::normalize(packet, true) {
-                            Ok(normalized_question) => normalized_question,
-                            Err(e) => {
-                                debug!("Error while parsing the question: {}", e);
-                                self.varz.client_queries_errors.inc();
-                                let _ = client.tcp_stream.shutdown(Shutdown::Both);
-                                continue;
-                            }
-                        };
-                        let cache_entry = self.cache.get2(&normalized_question);
-                        if let Some(mut cache_entry) = cache_entry {
-                            if !cache_entry.is_expired() {
-                                self.varz.client_queries_cached.inc();
-                                debug!("cached");
-                                let packet_len = cache_entry.packet.len();
-                                dns::set_tid(&mut cache_entry.packet, normalized_question.tid);
-                                dns::overwrite_qname(&mut cache_entry.packet,
-                                                     &normalized_question.qname);
-                                let mut write_buf = ByteBuf::with_capacity(TCP_QUERY_HEADER_SIZE +
-                                                                           packet_len);
-                                let binlen = [(packet_len >> 8) as u8, packet_len as u8];
-                                write_buf.copy_from_slice(&binlen);
-                                write_buf.copy_from_slice(&cache_entry.packet);
-                                let _ = client.tcp_stream.write(write_buf.bytes());
-                                let _ = client.tcp_stream.shutdown(Shutdown::Read);
-                                continue;
-                            }
-                            debug!("expired");
-                            self.varz.client_queries_expired.inc();
-                        }
+                if cache_decision.is_servable() {
+                    let cache_status = if cache_decision == CacheDecision::Stale {
+                        CacheStatus::Stale
+                    } else {
+                        CacheStatus::Hit
+                    };
+                    debug!("cached");
+                    dns::set_tid(&mut cache_entry.packet, normalized_question.tid);
+                    dns::overwrite_qname(&mut cache_entry.packet, &normalized_question.qname);
+                    client.queue_response(&cache_entry.packet);
+                    self.varz.client_response(normalized_question.qtype, &cache_entry.packet);
+                    self.query_log.log(client.peer_ip,
+                                       proto,
+                                       &normalized_question,
+                                       &cache_entry.packet,
+                                       cache_status,
+                                       ts);
+                    self.cached_latency.observe(duration_secs(ts.elapsed()));
+                    continue;
+                }
+                debug!("expired");
+            }
+            let client_query = ClientQuery {
+                proto: proto,
+                client_addr: client.tcp_stream.peer_addr().ok(),
+                udp_socket_idx: None,
+                client_tok: Some(client_tok),
+                tcpclient_tx: Some(self.tcpclient_tx.clone()),
+                normalized_question: normalized_question.clone(),
+                ts: Instant::now(),
+            };
+            client.pending_questions.push(PendingQuestion {
+                normalized_question: normalized_question,
+                deadline: ts + Duration::from_millis(UPSTREAM_TIMEOUT_MS),
+            });
+            let _ = self.resolver_tx.send(client_query);
+        }
+        if query_received {
+            if let Some(ref timeout) = client.timeout {
+                self.mio_timers.cancel_timeout(timeout);
+            }
+            client.timeout = self.mio_timers
+                .set_timeout(Duration::from_millis(self.max_tcp_idle_ms), client_tok)
+                .ok();
+        }
+    }
 
-                        let client_query = ClientQuery {
-                            proto: ClientQueryProtocol::TCP,
-                            client_addr: None,
-                            client_tok: Some(client_tok),
-                            tcpclient_tx: Some(self.tcpclient_tx.clone()),
-                            normalized_question: normalized_question.clone(),
-                            ts: Instant::now(),
-                        };
-                        client.normalized_question = Some(normalized_question);
-                        client.resolving = true;
-                        let _ = self.resolver_tx.send(client_query);
-                        client.interest.insert(Ready::writable());
-                    }
+    fn flush_client(&mut self, client_tok: Token) {
+        let client_idx = usize::from(client_tok) - 2;
+        let close = match self.clients[client_idx].as_mut() {
+            None => return,
+            Some(client) => {
+                if let Err(e) = client.flush() {
+                    debug!("{:?} Error while writing to socket: {:?}", client_tok, e);
+                    true
+                } else if client.eof && client.is_idle() {
+                    debug!("Client closed the connection and has no queries in flight");
+                    true
+                } else {
+                    self.mio_poll
+                        .reregister(&client.tcp_stream,
+                                    client_tok,
+                                    client.interest,
+                                    PollOpt::edge() | PollOpt::oneshot())
+                        .expect("Cannot reregister an event set for a client");
+                    false
                 }
             }
+        };
+        if close {
+            self.reset_connection(client_idx);
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.shutting_down = true;
+        let _ = self.mio_poll.deregister(&self.mio_listener);
+    }
+
+    fn drained(&self) -> bool {
+        self.clients.iter().all(|client| client.as_ref().map_or(true, |client| client.is_idle()))
+    }
+
+    fn close_all(&mut self) {
+        for client_idx in 0..self.clients.len() {
+            let open = match self.clients[client_idx] {
+                None => false,
+                Some(ref client) => {
+                    let _ = client.tcp_stream.shutdown(Shutdown::Both);
+                    true
+                }
+            };
+            if open {
+                self.reset_connection(client_idx);
+            }
         }
-        Ok(())
     }
 
     fn reset_connection(&mut self, client_idx: usize) {
@@ -349,6 +499,7 @@ impl TcpListenerHandler {
                 self.mio_timers.cancel_timeout(timeout);
             }
             client.timeout = None;
+            self.client_limiter.tcp_connection_closed(client.peer_ip);
         }
         self.clients[client_idx] = None;
     }
@@ -358,14 +509,15 @@ impl TcpListener {
     fn run(self, addr: String) -> io::Result<()> {
         let mio_poll = mio::Poll::new().expect("Couldn't instantiate an event loop");
         let mio_timers = timer::Builder::default()
-            .num_slots(MAX_TCP_CLIENTS / 256)
-            .capacity(MAX_TCP_CLIENTS)
+            .num_slots(self.max_tcp_clients / 256)
+            .capacity(self.max_tcp_clients)
             .build();
         mio_poll.register(&mio_timers, TIMER_TOK, Ready::readable(), PollOpt::edge())
             .expect("Could not register the timers");
-        let actual = addr.parse().expect("Unable to parse the TCP address to bind");
-        let mio_listener = tcp::TcpListener::bind(&actual).expect("Unable to bind the TCP socket");
-        debug!("tcp listener socket={:?}", mio_listener);
+        let mio_listener = socket_tcp_bound(&addr).expect("Unable to bind the TCP socket");
+        debug!("tcp listener socket={:?} tls={}",
+               mio_listener,
+               self.tls_config.is_some());
         self.service_ready_tx.send(1).unwrap();
         try!(mio_poll.register(&mio_listener,
                                LISTENER_TOK,
@@ -373,9 +525,15 @@ impl TcpListener {
                                PollOpt::edge() | PollOpt::oneshot()));
         let (tcpclient_tx, tcpclient_rx): (channel::SyncSender<ResolverResponse>,
                                            channel::Receiver<ResolverResponse>) =
-            channel::sync_channel(MAX_ACTIVE_QUERIES);
+            channel::sync_channel(self.max_active_queries);
         mio_poll.register(&tcpclient_rx, NOTIFY_TOK, Ready::all(), PollOpt::edge())
             .expect("Could not register the resolver channel");
+        let proto_name = if self.tls_config.is_some() {
+            ClientQueryProtocol::TLS.name()
+        } else {
+            ClientQueryProtocol::TCP.name()
+        };
+        let cached_latency = self.varz.client_latency.with_label_values(&[proto_name, "hit"]);
         let mut handler = TcpListenerHandler {
             mio_poll: mio_poll,
             mio_timers: mio_timers,
@@ -383,16 +541,31 @@ impl TcpListener {
             mio_listener: mio_listener,
             resolver_tx: self.resolver_tx.clone(),
             tcpclient_tx: tcpclient_tx,
-            clients: Vec::with_capacity(MAX_TCP_CLIENTS),
+            clients: Vec::with_capacity(self.max_tcp_clients),
+            acl: self.acl,
+            client_limiter: self.client_limiter,
+            heavy_hitters: self.heavy_hitters,
+            query_log: self.query_log,
             varz: self.varz,
+            tls_config: self.tls_config,
+            shutting_down: false,
+            max_tcp_idle_ms: self.max_tcp_idle_ms,
+            cached_latency: cached_latency,
         };
-        for _ in 0..MAX_TCP_CLIENTS {
+        for _ in 0..self.max_tcp_clients {
             handler.clients.push(None)
         }
-        info!("TCP listener is ready");
+        if handler.tls_config.is_some() {
+            info!("TLS listener is ready");
+        } else {
+            info!("TCP listener is ready");
+        }
+        let shutdown_timeout = Duration::from_secs(self.shutdown_timeout);
+        let mut shutdown_deadline = None;
         let mut events = mio::Events::with_capacity(MAX_EVENTS_PER_BATCH);
         loop {
-            match handler.mio_poll.poll(&mut events, None) {
+            match handler.mio_poll
+                .poll(&mut events, Some(Duration::from_millis(MAIN_LOOP_TICK_MS))) {
                 Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
                 Err(e) => return Err(e),
                 _ => {}
@@ -412,20 +585,55 @@ impl TcpListener {
                     token => handler.ready(token, event.kind()),
                 }
             }
+            if !signals::terminate_requested() {
+                continue;
+            }
+            if shutdown_deadline.is_none() {
+                handler.shutdown();
+                shutdown_deadline = Some(Instant::now() + shutdown_timeout);
+            }
+            if handler.drained() || Some(Instant::now()) >= shutdown_deadline {
+                handler.close_all();
+                break;
+            }
         }
+        debug!("TCP listener is shutting down");
+        Ok(())
     }
 
     pub fn spawn(rpdns_context: &RPDNSContext,
+                 listen_addr: String,
                  resolver_tx: channel::SyncSender<ClientQuery>,
                  service_ready_tx: mpsc::SyncSender<u8>)
                  -> io::Result<(thread::JoinHandle<()>)> {
+        Self::spawn_with_tls(rpdns_context,
+                             resolver_tx,
+                             service_ready_tx,
+                             listen_addr,
+                             None)
+    }
+
+    pub fn spawn_with_tls(rpdns_context: &RPDNSContext,
+                          resolver_tx: channel::SyncSender<ClientQuery>,
+                          service_ready_tx: mpsc::SyncSender<u8>,
+                          listen_addr: String,
+                          tls_config: Option<Arc<ServerConfig>>)
+                          -> io::Result<(thread::JoinHandle<()>)> {
         let tcp_listener = TcpListener {
             resolver_tx: resolver_tx,
             service_ready_tx: service_ready_tx,
             cache: rpdns_context.cache.clone(),
+            acl: Acl::new(&rpdns_context.config),
+            client_limiter: rpdns_context.client_limiter.clone(),
+            heavy_hitters: rpdns_context.heavy_hitters.clone(),
+            query_log: rpdns_context.query_log.clone(),
             varz: rpdns_context.varz.clone(),
+            tls_config: tls_config,
+            shutdown_timeout: rpdns_context.config.shutdown_timeout,
+            max_active_queries: rpdns_context.config.max_active_queries,
+            max_tcp_clients: rpdns_context.config.max_tcp_clients,
+            max_tcp_idle_ms: rpdns_context.config.max_tcp_idle_ms,
         };
-        let listen_addr = rpdns_context.listen_addr.clone();
         let tcp_listener_th = thread::spawn(move || {
             tcp_listener.run(listen_addr).expect("Unable to spawn a TCP listener");
         });
@@ -433,23 +641,28 @@ impl TcpListener {
     }
 }
 
-trait MapNonBlock<T> {
-    fn map_non_block(self) -> io::Result<Option<T>>;
-}
-
-impl<T> MapNonBlock<T> for io::Result<T> {
-    fn map_non_block(self) -> io::Result<Option<T>> {
-        use std::io::ErrorKind::WouldBlock;
-
-        match self {
-            Ok(value) => Ok(Some(value)),
-            Err(err) => {
-                if let WouldBlock = err.kind() {
-                    Ok(None)
-                } else {
-                    Err(err)
-                }
-            }
+fn socket_tcp_bound(addr: &str) -> io::Result<tcp::TcpListener> {
+    let actual: SocketAddr = addr.parse().expect("Unable to parse the TCP address to bind");
+    let nix_addr = SockAddr::Inet(InetAddr::from_std(&actual));
+    let socket_fd = match actual {
+        SocketAddr::V4(_) => {
+            try!(socket(AddressFamily::Inet,
+                        SockType::Stream,
+                        SockFlag::empty(),
+                        SockLevel::Tcp as i32))
         }
-    }
+        SocketAddr::V6(_) => {
+            let socket_fd = try!(socket(AddressFamily::Inet6,
+                                        SockType::Stream,
+                                        SockFlag::empty(),
+                                        SockLevel::Tcp as i32));
+            try!(set_ipv6_only(socket_fd));
+            socket_fd
+        }
+    };
+    try!(setsockopt(socket_fd, sockopt::ReuseAddr, &true));
+    try!(bind(socket_fd, &nix_addr));
+    try!(listen(socket_fd, TCP_BACKLOG));
+    let listener = unsafe { net::TcpListener::from_raw_fd(socket_fd) };
+    tcp::TcpListener::from_listener(listener, &actual)
 }
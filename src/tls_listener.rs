@@ -0,0 +1,69 @@
+use client_query::*;
+use mio::*;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use super::RPDNSContext;
+use tcp_listener::TcpListener;
+
+pub struct TlsListener;
+
+impl TlsListener {
+    pub fn spawn(rpdns_context: &RPDNSContext,
+                 resolver_tx: channel::SyncSender<ClientQuery>,
+                 service_ready_tx: mpsc::SyncSender<u8>)
+                 -> io::Result<(thread::JoinHandle<()>)> {
+        let config = &rpdns_context.config;
+        let cert_file = config.tls_cert_file.as_ref().expect("Missing TLS certificate file");
+        let key_file = config.tls_key_file.as_ref().expect("Missing TLS key file");
+        let tls_config = try!(tls_server_config(cert_file, key_file));
+        let listen_addr = config.tls_listen_addr.clone();
+        TcpListener::spawn_with_tls(rpdns_context,
+                                    resolver_tx,
+                                    service_ready_tx,
+                                    listen_addr,
+                                    Some(Arc::new(tls_config)))
+    }
+}
+
+fn load_certs(cert_file: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(try!(File::open(cert_file)));
+    match certs(&mut reader) {
+        Ok(ref certs) if !certs.is_empty() => Ok(certs.clone()),
+        _ => {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                               "No valid certificates found in the TLS certificate file"))
+        }
+    }
+}
+
+fn load_private_key(key_file: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(try!(File::open(key_file)));
+    if let Ok(mut keys) = pkcs8_private_keys(&mut reader) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+    try!(reader.seek(SeekFrom::Start(0)));
+    if let Ok(mut keys) = rsa_private_keys(&mut reader) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData,
+                       "No valid private keys found in the TLS key file"))
+}
+
+fn tls_server_config(cert_file: &str, key_file: &str) -> io::Result<ServerConfig> {
+    let certs = try!(load_certs(cert_file));
+    let private_key = try!(load_private_key(key_file));
+    let mut tls_config = ServerConfig::new();
+    tls_config.set_single_cert(certs, private_key);
+    info!("TLS certificate loaded from {}", cert_file);
+    Ok(tls_config)
+}
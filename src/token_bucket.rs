@@ -0,0 +1,102 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    tokens: u32,
+    ts: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: rate,
+            ts: now,
+        }
+    }
+
+    pub fn take(&mut self, rate: u32, now: Instant) -> bool {
+        let elapsed = if now > self.ts {
+            now.duration_since(self.ts)
+        } else {
+            Duration::new(0, 0)
+        };
+        if elapsed.as_secs() > 0 {
+            self.tokens = rate;
+            self.ts = now;
+        } else {
+            let new_tokens = elapsed.subsec_nanos() as u64 * rate as u64 / 1_000_000_000;
+            if new_tokens > 0 {
+                self.tokens = cmp::min(rate as u64, self.tokens as u64 + new_tokens) as u32;
+                self.ts += Duration::new(0, (new_tokens * 1_000_000_000 / rate as u64) as u32);
+            }
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenBucket;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_initial_burst() {
+        let now = Instant::now();
+        let mut token_bucket = TokenBucket::new(3, now);
+        assert!(token_bucket.take(3, now));
+        assert!(token_bucket.take(3, now));
+        assert!(token_bucket.take(3, now));
+        assert!(!token_bucket.take(3, now));
+    }
+
+    #[test]
+    fn test_fractional_refill_keeps_remainder() {
+        let start = Instant::now();
+        let mut token_bucket = TokenBucket::new(4, start);
+        for _ in 0..4 {
+            assert!(token_bucket.take(4, start));
+        }
+        // 300ms at 4 tokens/s is 1.2 tokens: one token, 50ms carried over
+        assert!(token_bucket.take(4, start + Duration::from_millis(300)));
+        assert!(!token_bucket.take(4, start + Duration::from_millis(300)));
+        assert_eq!(token_bucket.ts, start + Duration::from_millis(250));
+        assert!(!token_bucket.take(4, start + Duration::from_millis(450)));
+        assert!(token_bucket.take(4, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_full_refill_after_one_second() {
+        let start = Instant::now();
+        let mut token_bucket = TokenBucket::new(2, start);
+        assert!(token_bucket.take(2, start));
+        assert!(token_bucket.take(2, start));
+        assert!(!token_bucket.take(2, start));
+        let later = start + Duration::from_millis(1500);
+        assert!(token_bucket.take(2, later));
+        assert!(token_bucket.take(2, later));
+        assert!(!token_bucket.take(2, later));
+        assert_eq!(token_bucket.ts, later);
+    }
+
+    #[test]
+    fn test_clock_behind_bucket() {
+        let start = Instant::now();
+        let mut token_bucket = TokenBucket::new(1, start + Duration::from_millis(10));
+        assert!(token_bucket.take(1, start));
+        assert!(!token_bucket.take(1, start));
+    }
+
+    #[test]
+    fn test_zero_rate() {
+        let start = Instant::now();
+        let mut token_bucket = TokenBucket::new(0, start);
+        assert!(!token_bucket.take(0, start));
+        assert!(!token_bucket.take(0, start + Duration::from_millis(500)));
+        assert!(!token_bucket.take(0, start + Duration::from_secs(2)));
+    }
+}
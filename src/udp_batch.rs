@@ -0,0 +1,282 @@
+use libc;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::RawFd;
+
+pub struct RecvBatch {
+    packets: Vec<Vec<u8>>,
+    lens: Vec<usize>,
+    addrs: Vec<libc::sockaddr_storage>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    iovecs: Vec<libc::iovec>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    msgs: Vec<libc::mmsghdr>,
+}
+
+// The raw pointers in the iovecs and message headers are what keep the batch
+// from being `Send`. They only point into heap allocations owned by the batch
+// itself: the packet buffers, and the `addrs` and `iovecs` vectors. Moving a
+// `RecvBatch` moves the vector headers, not the heap storage they refer to,
+// and none of these vectors is ever resized after `new()`, so the pointers
+// remain valid wherever the batch goes. Nothing else holds them, and the
+// batch can only be used through `&mut self`, so no other thread can access
+// the buffers concurrently.
+unsafe impl Send for RecvBatch {}
+
+impl RecvBatch {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn new(batch_size: usize, packet_size: usize) -> RecvBatch {
+        let mut packets: Vec<Vec<u8>> = (0..batch_size).map(|_| vec![0u8; packet_size]).collect();
+        let mut addrs: Vec<libc::sockaddr_storage> = (0..batch_size)
+            .map(|_| unsafe { mem::zeroed() })
+            .collect();
+        let mut iovecs: Vec<libc::iovec> = packets.iter_mut()
+            .map(|packet| {
+                libc::iovec {
+                    iov_base: packet.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: packet.len(),
+                }
+            })
+            .collect();
+        let msgs: Vec<libc::mmsghdr> = (0..batch_size)
+            .map(|i| {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+                msg.msg_hdr.msg_iov = &mut iovecs[i];
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+        RecvBatch {
+            packets: packets,
+            lens: vec![0; batch_size],
+            addrs: addrs,
+            iovecs: iovecs,
+            msgs: msgs,
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn new(batch_size: usize, packet_size: usize) -> RecvBatch {
+        let batch_size = if batch_size > 0 { 1 } else { 0 };
+        RecvBatch {
+            packets: (0..batch_size).map(|_| vec![0u8; packet_size]).collect(),
+            lens: vec![0; batch_size],
+            addrs: (0..batch_size).map(|_| unsafe { mem::zeroed() }).collect(),
+        }
+    }
+
+    pub fn get(&self, i: usize) -> (&[u8], Option<SocketAddr>) {
+        (&self.packets[i][..self.lens[i]], sockaddr_to_std(&self.addrs[i]))
+    }
+
+    pub fn get_mut(&mut self, i: usize) -> (&mut [u8], Option<SocketAddr>) {
+        let client_addr = sockaddr_to_std(&self.addrs[i]);
+        (&mut self.packets[i][..self.lens[i]], client_addr)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn recv_batch(socket_fd: RawFd, batch: &mut RecvBatch, nonblocking: bool) -> io::Result<usize> {
+    let batch_size = batch.msgs.len();
+    for msg in &mut batch.msgs {
+        msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_len = 0;
+    }
+    let flags = if nonblocking {
+        libc::MSG_DONTWAIT
+    } else {
+        libc::MSG_WAITFORONE
+    };
+    let ret = unsafe {
+        libc::recvmmsg(socket_fd,
+                       batch.msgs.as_mut_ptr(),
+                       batch_size as libc::c_uint,
+                       flags as _,
+                       ::std::ptr::null_mut())
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let count = ret as usize;
+    for i in 0..count {
+        batch.lens[i] = batch.msgs[i].msg_len as usize;
+    }
+    Ok(count)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn recv_batch(socket_fd: RawFd, batch: &mut RecvBatch, nonblocking: bool) -> io::Result<usize> {
+    if batch.packets.is_empty() {
+        return Ok(0);
+    }
+    let flags = if nonblocking { libc::MSG_DONTWAIT } else { 0 };
+    let mut addr_len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::recvfrom(socket_fd,
+                       batch.packets[0].as_mut_ptr() as *mut libc::c_void,
+                       batch.packets[0].len(),
+                       flags,
+                       &mut batch.addrs[0] as *mut _ as *mut libc::sockaddr,
+                       &mut addr_len)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    batch.lens[0] = ret as usize;
+    Ok(1)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn send_batch(socket_fd: RawFd, responses: &[(Vec<u8>, SocketAddr)]) -> io::Result<()> {
+    let mut addrs: Vec<libc::sockaddr_storage> = (0..responses.len())
+        .map(|_| unsafe { mem::zeroed() })
+        .collect();
+    let mut iovecs: Vec<libc::iovec> = responses.iter()
+        .map(|&(ref packet, _)| {
+            libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            }
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(responses.len());
+    for (i, &(_, ref client_addr)) in responses.iter().enumerate() {
+        let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+        msg.msg_hdr.msg_namelen = std_to_sockaddr(client_addr, &mut addrs[i]);
+        msg.msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+        msg.msg_hdr.msg_iov = &mut iovecs[i];
+        msg.msg_hdr.msg_iovlen = 1;
+        msgs.push(msg);
+    }
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let ret = unsafe {
+            libc::sendmmsg(socket_fd,
+                           msgs[sent..].as_mut_ptr(),
+                           (msgs.len() - sent) as libc::c_uint,
+                           0)
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if ret == 0 {
+            break;
+        }
+        sent += ret as usize;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn send_batch(socket_fd: RawFd, responses: &[(Vec<u8>, SocketAddr)]) -> io::Result<()> {
+    for &(ref packet, ref client_addr) in responses {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let addr_len = std_to_sockaddr(client_addr, &mut addr);
+        unsafe {
+            libc::sendto(socket_fd,
+                         packet.as_ptr() as *const libc::c_void,
+                         packet.len(),
+                         0,
+                         &addr as *const _ as *const libc::sockaddr,
+                         addr_len);
+        }
+    }
+    Ok(())
+}
+
+fn sockaddr_to_std(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip,
+                                                  u16::from_be(sin6.sin6_port),
+                                                  u32::from_be(sin6.sin6_flowinfo),
+                                                  sin6.sin6_scope_id)))
+        }
+        _ => None,
+    }
+}
+
+fn std_to_sockaddr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match *addr {
+        SocketAddr::V4(ref addr) => {
+            let sin = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        }
+        SocketAddr::V6(ref addr) => {
+            let sin6 = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    fn bind() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    }
+
+    #[test]
+    fn test_loopback_round_trip() {
+        let client = bind();
+        let server = bind();
+        let client_addr = client.local_addr().unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let queries: Vec<(Vec<u8>, SocketAddr)> = (0..3u8)
+            .map(|i| (vec![i; 10 + i as usize], server_addr))
+            .collect();
+        send_batch(client.as_raw_fd(), &queries).unwrap();
+
+        let mut batch = RecvBatch::new(4, 512);
+        let mut received = Vec::new();
+        while received.len() < queries.len() {
+            let count = recv_batch(server.as_raw_fd(), &mut batch, false).unwrap();
+            assert!(count > 0);
+            for i in 0..count {
+                let (packet, peer_addr) = batch.get(i);
+                assert_eq!(peer_addr, Some(client_addr));
+                received.push(packet.to_vec());
+            }
+        }
+        let sent: Vec<Vec<u8>> = queries.iter().map(|&(ref packet, _)| packet.clone()).collect();
+        assert_eq!(received, sent);
+
+        let responses: Vec<(Vec<u8>, SocketAddr)> = received.into_iter()
+            .map(|packet| (packet, client_addr))
+            .collect();
+        send_batch(server.as_raw_fd(), &responses).unwrap();
+        let mut buf = [0u8; 512];
+        for &(ref packet, _) in &responses {
+            let (len, peer_addr) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(peer_addr, server_addr);
+            assert_eq!(&buf[..len], &packet[..]);
+        }
+    }
+}
@@ -1,27 +1,45 @@
-use cache::Cache;
+use acl::{Acl, AclAction};
+use cache::{Cache, CacheDecision};
+use client_limiter::ClientLimiter;
 use client_query::*;
-use dns;
+use dns::{self, NormalizedQuestion};
+use heavy_hitters::HeavyHitters;
 use mio::*;
+use net::set_ipv6_only;
+use prometheus::{Counter, Histogram};
+use query_log::{CacheStatus, QueryLog};
 use nix::sys::socket::{bind, setsockopt, sockopt, AddressFamily, SockFlag, SockType, SockLevel,
                        SockAddr, socket, InetAddr};
+use rrl::{Rrl, RrlAction};
+use signals;
 use std::io;
 use std::net::{UdpSocket, SocketAddr};
-use std::os::unix::io::{RawFd, FromRawFd};
+use std::os::unix::io::{AsRawFd, RawFd, FromRawFd};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::mpsc;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use super::RPDNSContext;
-use varz::Varz;
+use udp_batch::{self, RecvBatch};
+use varz::{duration_secs, Varz};
 
-use super::{UDP_BUFFER_SIZE, DNS_MAX_UDP_SIZE, DNS_QUERY_MIN_SIZE, DNS_QUERY_MAX_SIZE};
+use super::{DNS_MAX_UDP_SIZE, DNS_QUERY_MIN_SIZE, DNS_QUERY_MAX_SIZE, MAIN_LOOP_TICK_MS,
+            MAX_EVENTS_PER_BATCH};
 
 pub struct UdpListener {
     socket: UdpSocket,
+    udp_socket_idx: usize,
+    thread_queries: Counter,
+    cached_latency: Histogram,
     resolver_tx: channel::SyncSender<ClientQuery>,
     service_ready_tx: mpsc::SyncSender<u8>,
     cache: Cache,
+    acl: Acl,
+    client_limiter: ClientLimiter,
+    rrl: Rrl,
+    heavy_hitters: HeavyHitters,
+    query_log: QueryLog,
     varz: Arc<Varz>,
 }
 
@@ -29,67 +47,167 @@ impl UdpListener {
     fn run(mut self) -> io::Result<()> {
         debug!("udp listener socket={:?}", self.socket);
         self.service_ready_tx.send(0).unwrap();
-        let mut packet = [0u8; DNS_MAX_UDP_SIZE];
-        loop {
-            let (count, client_addr) =
-                self.socket.recv_from(&mut packet).expect("UDP socket error");
-            self.varz.client_queries_udp.inc();
-            if count < DNS_QUERY_MIN_SIZE || count > DNS_QUERY_MAX_SIZE {
-                info!("Short query using UDP");
+        try!(self.socket.set_read_timeout(Some(Duration::from_millis(MAIN_LOOP_TICK_MS))));
+        let socket_fd = self.socket.as_raw_fd();
+        let mut recv_batch = RecvBatch::new(MAX_EVENTS_PER_BATCH, DNS_MAX_UDP_SIZE);
+        let mut responses = Vec::with_capacity(MAX_EVENTS_PER_BATCH);
+        while !signals::terminate_requested() {
+            let count = match udp_batch::recv_batch(socket_fd, &mut recv_batch, false) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted ||
+                              e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => continue,
+                res => res.expect("UDP socket error"),
+            };
+            for i in 0..count {
+                let (packet, client_addr) = match recv_batch.get(i) {
+                    (packet, Some(client_addr)) => (packet, client_addr),
+                    _ => continue,
+                };
+                if let Some(response) = self.handle_query(packet, client_addr) {
+                    responses.push((response, client_addr));
+                }
+            }
+            if !responses.is_empty() {
+                let _ = udp_batch::send_batch(socket_fd, &responses);
+                responses.clear();
+            }
+        }
+        debug!("UDP listener is shutting down");
+        Ok(())
+    }
+
+    fn handle_query(&mut self, packet: &[u8], client_addr: SocketAddr) -> Option<Vec<u8>> {
+        let ts = Instant::now();
+        self.varz.client_queries_udp.inc();
+        self.thread_queries.inc();
+        let denied = !self.acl.allows(client_addr.ip());
+        if denied {
+            debug!("Query from {} denied by the ACL", client_addr);
+            self.varz.client_queries_denied.inc();
+            if self.acl.action == AclAction::Drop {
+                return None;
+            }
+        }
+        // Spoofable sources make any response, even REFUSED, usable for reflection
+        if !self.client_limiter.query_allowed(client_addr.ip()) {
+            return None;
+        }
+        let count = packet.len();
+        if count < DNS_QUERY_MIN_SIZE || count > DNS_QUERY_MAX_SIZE {
+            info!("Short query using UDP");
+            self.varz.client_queries_errors.inc();
+            return None;
+        }
+        let normalized_question = match dns::normalize(packet, true) {
+            Ok(normalized_question) => normalized_question,
+            Err(e) => {
+                debug!("Error while parsing the question: {}", e);
                 self.varz.client_queries_errors.inc();
-                continue;
+                return None;
             }
-            let packet = &packet[..count];
-            let normalized_question = match dns::normalize(packet, true) {
-                Ok(normalized_question) => normalized_question,
-                Err(e) => {
-                    debug!("Error while parsing the question: {}", e);
-                    self.varz.client_queries_errors.inc();
-                    continue;
+        };
+        self.heavy_hitters.record(client_addr.ip(), &normalized_question.qname);
+        if denied {
+            return dns::build_refused_packet(&normalized_question).ok().and_then(|packet| {
+                self.respond(client_addr, &normalized_question, packet, CacheStatus::None, ts)
+            });
+        }
+        if let Some((mut cache_entry, cache_decision)) = self.cache.lookup(&normalized_question) {
+            self.varz.client_cache_lookup(cache_decision);
+            if cache_decision.needs_refresh() {
+                let _ = self.resolver_tx.send(ClientQuery::refresh(&normalized_question));
+            }
+            if cache_decision.is_servable() {
+                let rrl_action = self.rrl.check(client_addr.ip(),
+                                                &normalized_question.qname,
+                                                &cache_entry.packet);
+                if rrl_action == RrlAction::Drop {
+                    debug!("cached, but dropped by RRL");
+                    return None;
                 }
-            };
-            let cache_entry = self.cache.get2(&normalized_question);
-            if let Some(mut cache_entry) = cache_entry {
-                if !cache_entry.is_expired() {
-                    self.varz.client_queries_cached.inc();
-                    if cache_entry.packet.len() > normalized_question.payload_size as usize {
-                        debug!("cached, but has to be truncated");
-                        let packet = dns::build_tc_packet(&normalized_question).unwrap();
-                        let _ = self.socket.send_to(&packet, &client_addr);
-                        continue;
-                    }
-                    debug!("cached");
-                    dns::set_tid(&mut cache_entry.packet, normalized_question.tid);
-                    dns::overwrite_qname(&mut cache_entry.packet, &normalized_question.qname);
-                    let _ = self.socket.send_to(&cache_entry.packet, &client_addr);
-                    continue;
+                let cache_status = if cache_decision == CacheDecision::Stale {
+                    CacheStatus::Stale
+                } else {
+                    CacheStatus::Hit
+                };
+                self.cached_latency.observe(duration_secs(ts.elapsed()));
+                if rrl_action == RrlAction::Slip ||
+                   cache_entry.packet.len() > normalized_question.payload_size as usize {
+                    debug!("cached, but has to be truncated");
+                    return dns::build_tc_packet(&normalized_question).ok().and_then(|packet| {
+                        self.respond(client_addr, &normalized_question, packet, cache_status, ts)
+                    });
                 }
-                debug!("expired");
-                self.varz.client_queries_expired.inc();
+                debug!("cached");
+                dns::set_tid(&mut cache_entry.packet, normalized_question.tid);
+                dns::overwrite_qname(&mut cache_entry.packet, &normalized_question.qname);
+                return self.respond(client_addr,
+                                    &normalized_question,
+                                    cache_entry.packet,
+                                    cache_status,
+                                    ts);
             }
-            let client_query = ClientQuery {
-                proto: ClientQueryProtocol::UDP,
-                client_tok: None,
-                client_addr: Some(client_addr),
-                tcpclient_tx: None,
-                normalized_question: normalized_question,
-                ts: Instant::now(),
-            };
-            let _ = self.resolver_tx.send(client_query);
+            debug!("expired");
         }
+        let client_query = ClientQuery {
+            proto: ClientQueryProtocol::UDP,
+            client_tok: None,
+            client_addr: Some(client_addr),
+            udp_socket_idx: Some(self.udp_socket_idx),
+            tcpclient_tx: None,
+            normalized_question: normalized_question,
+            ts: Instant::now(),
+        };
+        let _ = self.resolver_tx.send(client_query);
+        None
+    }
+
+    fn respond(&self,
+               client_addr: SocketAddr,
+               normalized_question: &NormalizedQuestion,
+               packet: Vec<u8>,
+               cache_status: CacheStatus,
+               ts: Instant)
+               -> Option<Vec<u8>> {
+        self.varz.client_response(normalized_question.qtype, &packet);
+        self.query_log.log(client_addr.ip(),
+                           ClientQueryProtocol::UDP,
+                           normalized_question,
+                           &packet,
+                           cache_status,
+                           ts);
+        Some(packet)
     }
 
     pub fn spawn(rpdns_context: &RPDNSContext,
+                 udp_socket_idx: usize,
                  resolver_tx: channel::SyncSender<ClientQuery>,
                  service_ready_tx: mpsc::SyncSender<u8>)
                  -> io::Result<(thread::JoinHandle<()>)> {
-        let udp_socket =
-            rpdns_context.udp_socket.try_clone().expect("Unable to clone the UDP listening socket");
+        let udp_socket = rpdns_context.udp_sockets[udp_socket_idx]
+            .try_clone()
+            .expect("Unable to clone the UDP listening socket");
+        let udp_listener_threads = rpdns_context.config.udp_listener_threads;
+        let listen_addr = &rpdns_context.listen_addrs[udp_socket_idx / udp_listener_threads];
+        let thread_id = (udp_socket_idx % udp_listener_threads).to_string();
+        let thread_queries = rpdns_context.varz
+            .udp_listener_queries
+            .with_label_values(&[listen_addr.as_str(), thread_id.as_str()]);
         let udp_listener = UdpListener {
             socket: udp_socket,
+            udp_socket_idx: udp_socket_idx,
+            thread_queries: thread_queries,
+            cached_latency: rpdns_context.varz
+                .client_latency
+                .with_label_values(&[ClientQueryProtocol::UDP.name(), "hit"]),
             resolver_tx: resolver_tx,
             service_ready_tx: service_ready_tx,
             cache: rpdns_context.cache.clone(),
+            acl: Acl::new(&rpdns_context.config),
+            client_limiter: rpdns_context.client_limiter.clone(),
+            rrl: rpdns_context.rrl.clone(),
+            heavy_hitters: rpdns_context.heavy_hitters.clone(),
+            query_log: rpdns_context.query_log.clone(),
             varz: rpdns_context.varz.clone(),
         };
         let udp_listener_th = thread::spawn(move || {
@@ -101,15 +219,15 @@ impl UdpListener {
 }
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
-fn socket_udp_set_buffer_size(socket_fd: RawFd) {
-    let _ = setsockopt(socket_fd, sockopt::SndBufForce, &UDP_BUFFER_SIZE);
-    let _ = setsockopt(socket_fd, sockopt::RcvBufForce, &UDP_BUFFER_SIZE);
+fn socket_udp_set_buffer_size(socket_fd: RawFd, buffer_size: usize) {
+    let _ = setsockopt(socket_fd, sockopt::SndBufForce, &buffer_size);
+    let _ = setsockopt(socket_fd, sockopt::RcvBufForce, &buffer_size);
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
-fn socket_udp_set_buffer_size(socket_fd: RawFd) {
-    let _ = setsockopt(socket_fd, sockopt::SndBuf, &UDP_BUFFER_SIZE);
-    let _ = setsockopt(socket_fd, sockopt::RcvBuf, &UDP_BUFFER_SIZE);
+fn socket_udp_set_buffer_size(socket_fd: RawFd, buffer_size: usize) {
+    let _ = setsockopt(socket_fd, sockopt::SndBuf, &buffer_size);
+    let _ = setsockopt(socket_fd, sockopt::RcvBuf, &buffer_size);
 }
 
 fn socket_udp_v4() -> io::Result<RawFd> {
@@ -128,16 +246,20 @@ fn socket_udp_v6() -> io::Result<RawFd> {
     Ok(socket_fd)
 }
 
-pub fn socket_udp_bound(addr: &str) -> io::Result<UdpSocket> {
+pub fn socket_udp_bound(addr: &str, buffer_size: usize) -> io::Result<UdpSocket> {
     let actual: SocketAddr = FromStr::from_str(addr).expect("Invalid address");
     let nix_addr = SockAddr::Inet(InetAddr::from_std(&actual));
     let socket_fd = match actual {
         SocketAddr::V4(_) => try!(socket_udp_v4()),
-        SocketAddr::V6(_) => try!(socket_udp_v6()),
+        SocketAddr::V6(_) => {
+            let socket_fd = try!(socket_udp_v6());
+            try!(set_ipv6_only(socket_fd));
+            socket_fd
+        }
     };
     let _ = setsockopt(socket_fd, sockopt::ReuseAddr, &true);
     let _ = setsockopt(socket_fd, sockopt::ReusePort, &true);
-    socket_udp_set_buffer_size(socket_fd);
+    socket_udp_set_buffer_size(socket_fd, buffer_size);
     bind(socket_fd, &nix_addr).expect("Unable to bind a UDP socket");
     let socket: UdpSocket = unsafe { UdpSocket::from_raw_fd(socket_fd) };
     Ok(socket)
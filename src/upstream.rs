@@ -0,0 +1,43 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+const UPSTREAM_TLS_SCHEME: &'static str = "tls://";
+
+/// An upstream server, written either as `ip:port` or as
+/// `tls://ip:port#server_name`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamAddr {
+    pub remote_addr: String,
+    pub socket_addr: SocketAddr,
+    pub tls_server_name: Option<String>,
+}
+
+impl FromStr for UpstreamAddr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<UpstreamAddr, &'static str> {
+        let (addr, tls_server_name) = if s.starts_with(UPSTREAM_TLS_SCHEME) {
+            let mut addr_and_name = s[UPSTREAM_TLS_SCHEME.len()..].splitn(2, '#');
+            let addr = addr_and_name.next().unwrap();
+            match addr_and_name.next() {
+                None | Some("") => {
+                    return Err("TLS upstream servers require a name, such as \
+                                tls://9.9.9.9:853#dns.quad9.net")
+                }
+                Some(server_name) => (addr, Some(server_name.to_owned())),
+            }
+        } else {
+            (s, None)
+        };
+        match addr.parse() {
+            Err(_) => Err("Unable to parse an upstream resolver address"),
+            Ok(socket_addr) => {
+                Ok(UpstreamAddr {
+                    remote_addr: s.to_owned(),
+                    socket_addr: socket_addr,
+                    tls_server_name: tls_server_name,
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use dns::NormalizedQuestionKey;
+use mio::*;
+use mio::timer::Timeout;
+use std::io;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+
+use super::{DNS_MAX_TCP_SIZE, DNS_QUERY_MIN_SIZE};
+use tcp_listener::TCP_QUERY_HEADER_SIZE;
+
+pub struct UpstreamTcpQuery {
+    pub normalized_question_key: NormalizedQuestionKey,
+    pub socket_addr: SocketAddr,
+    pub tcp_stream: tcp::TcpStream,
+    pub timeout: Option<Timeout>,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    read_buf: Vec<u8>,
+}
+
+impl UpstreamTcpQuery {
+    pub fn new(normalized_question_key: NormalizedQuestionKey,
+               socket_addr: SocketAddr,
+               query_packet: &[u8])
+               -> io::Result<UpstreamTcpQuery> {
+        let tcp_stream = try!(tcp::TcpStream::connect(&socket_addr));
+        let packet_len = query_packet.len();
+        let mut write_buf = Vec::with_capacity(TCP_QUERY_HEADER_SIZE + packet_len);
+        write_buf.push((packet_len >> 8) as u8);
+        write_buf.push(packet_len as u8);
+        write_buf.extend_from_slice(query_packet);
+        Ok(UpstreamTcpQuery {
+            normalized_question_key: normalized_question_key,
+            socket_addr: socket_addr,
+            tcp_stream: tcp_stream,
+            timeout: None,
+            write_buf: write_buf,
+            write_pos: 0,
+            read_buf: Vec::new(),
+        })
+    }
+
+    pub fn interest(&self) -> Ready {
+        if self.write_pos < self.write_buf.len() {
+            Ready::writable() | Ready::hup() | Ready::error()
+        } else {
+            Ready::readable() | Ready::hup() | Ready::error()
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        while self.write_pos < self.write_buf.len() {
+            match self.tcp_stream.write(&self.write_buf[self.write_pos..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "Upstream server closed the connection"))
+                }
+                Ok(count) => self.write_pos += count,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_response(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk = [0u8; 4096];
+        let mut eof = false;
+        loop {
+            match self.tcp_stream.read(&mut chunk) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
+                Ok(count) => self.read_buf.extend_from_slice(&chunk[..count]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+            if self.read_buf.len() > TCP_QUERY_HEADER_SIZE + DNS_MAX_TCP_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "Oversized response from an upstream server"));
+            }
+        }
+        if self.read_buf.len() < TCP_QUERY_HEADER_SIZE {
+            return Self::incomplete(eof);
+        }
+        let expected_len = ((self.read_buf[0] as usize) << 8) | self.read_buf[1] as usize;
+        if expected_len < DNS_QUERY_MIN_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Short response from an upstream server"));
+        }
+        if self.read_buf.len() < TCP_QUERY_HEADER_SIZE + expected_len {
+            return Self::incomplete(eof);
+        }
+        let response = self.read_buf[TCP_QUERY_HEADER_SIZE..TCP_QUERY_HEADER_SIZE + expected_len]
+            .to_vec();
+        Ok(Some(response))
+    }
+
+    fn incomplete(eof: bool) -> io::Result<Option<Vec<u8>>> {
+        if eof {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "Upstream server closed the connection"));
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,321 @@
+use mio;
+use mio::*;
+use rustls::{ClientConfig, ClientSession, Session};
+use slab;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::usize;
+use webpki_roots;
+
+use super::{DNS_MAX_TCP_SIZE, MAX_UPSTREAM_TLS_CONNECTIONS, UPSTREAM_TLS_CONNECTIONS_PER_SERVER,
+            UPSTREAM_TLS_IDLE_TIMEOUT_MS};
+use tcp_listener::TCP_QUERY_HEADER_SIZE;
+
+pub const UPSTREAM_TLS_TOK_BASE: usize = 196608;
+
+type Slab<T> = slab::Slab<T, usize>;
+
+pub struct UpstreamTlsConnection {
+    pub id: usize,
+    pub upstream_server_idx: usize,
+    pub socket_addr: SocketAddr,
+    tcp_stream: tcp::TcpStream,
+    tls_session: ClientSession,
+    read_buf: Vec<u8>,
+    last_used: Instant,
+    closed: bool,
+}
+
+impl UpstreamTlsConnection {
+    fn new(id: usize,
+           upstream_server_idx: usize,
+           socket_addr: SocketAddr,
+           server_name: &str,
+           tls_config: &Arc<ClientConfig>)
+           -> io::Result<UpstreamTlsConnection> {
+        let tcp_stream = try!(tcp::TcpStream::connect(&socket_addr));
+        let _ = tcp_stream.set_nodelay(true);
+        Ok(UpstreamTlsConnection {
+            id: id,
+            upstream_server_idx: upstream_server_idx,
+            socket_addr: socket_addr,
+            tcp_stream: tcp_stream,
+            tls_session: ClientSession::new(tls_config, server_name),
+            read_buf: Vec::new(),
+            last_used: Instant::now(),
+            closed: false,
+        })
+    }
+
+    fn interest(&self) -> Ready {
+        let mut interest = Ready::readable() | Ready::hup() | Ready::error();
+        if self.tls_session.wants_write() {
+            interest.insert(Ready::writable());
+        }
+        interest
+    }
+
+    fn send_query(&mut self, query_packet: &[u8]) -> io::Result<()> {
+        let packet_len = query_packet.len();
+        let binlen = [(packet_len >> 8) as u8, packet_len as u8];
+        try!(self.tls_session.write_all(&binlen));
+        try!(self.tls_session.write_all(query_packet));
+        self.last_used = Instant::now();
+        self.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while self.tls_session.wants_write() {
+            match self.tls_session.write_tls(&mut self.tcp_stream) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "Upstream server closed the connection"))
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_responses(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        loop {
+            match self.tls_session.read_tls(&mut self.tcp_stream) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+            if let Err(e) = self.tls_session.process_new_packets() {
+                info!("TLS error with upstream server {:?}: {:?}", self.socket_addr, e);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "TLS error"));
+            }
+            try!(self.tls_session.read_to_end(&mut self.read_buf));
+        }
+        let mut responses = Vec::new();
+        loop {
+            let bytes_len = self.read_buf.len();
+            if bytes_len < TCP_QUERY_HEADER_SIZE {
+                break;
+            }
+            let expected_len = ((self.read_buf[0] as usize) << 8) | self.read_buf[1] as usize;
+            if expected_len > DNS_MAX_TCP_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "Oversized response from an upstream server"));
+            }
+            if bytes_len < TCP_QUERY_HEADER_SIZE + expected_len {
+                break;
+            }
+            responses.push(self.read_buf[TCP_QUERY_HEADER_SIZE..
+                                         TCP_QUERY_HEADER_SIZE + expected_len]
+                .to_vec());
+            self.read_buf.drain(..TCP_QUERY_HEADER_SIZE + expected_len);
+        }
+        if !responses.is_empty() {
+            self.last_used = Instant::now();
+        }
+        Ok(responses)
+    }
+}
+
+pub struct UpstreamTlsPool {
+    tls_config: Arc<ClientConfig>,
+    connections: Slab<UpstreamTlsConnection>,
+    server_connections: HashMap<usize, Vec<usize>>,
+    closed: Vec<usize>,
+    next: usize,
+    next_id: usize,
+}
+
+impl UpstreamTlsPool {
+    pub fn new() -> UpstreamTlsPool {
+        let mut tls_config = ClientConfig::new();
+        tls_config.root_store.add_trust_anchors(&webpki_roots::ROOTS);
+        UpstreamTlsPool {
+            tls_config: Arc::new(tls_config),
+            connections: Slab::with_capacity(MAX_UPSTREAM_TLS_CONNECTIONS),
+            server_connections: HashMap::new(),
+            closed: Vec::new(),
+            next: 0,
+            next_id: 0,
+        }
+    }
+
+    fn connect(&mut self,
+               mio_poll: &mio::Poll,
+               upstream_server_idx: usize,
+               socket_addr: SocketAddr,
+               server_name: &str)
+               -> io::Result<usize> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let connection = try!(UpstreamTlsConnection::new(self.next_id,
+                                                         upstream_server_idx,
+                                                         socket_addr,
+                                                         server_name,
+                                                         &self.tls_config));
+        let connection_idx = match self.connections.insert(connection) {
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "Too many upstream TLS connections"))
+            }
+            Ok(connection_idx) => connection_idx,
+        };
+        let connection = &self.connections[connection_idx];
+        try!(mio_poll.register(&connection.tcp_stream,
+                               Token(UPSTREAM_TLS_TOK_BASE + connection_idx),
+                               connection.interest(),
+                               PollOpt::edge() | PollOpt::oneshot()));
+        self.server_connections
+            .entry(upstream_server_idx)
+            .or_insert_with(Vec::new)
+            .push(connection_idx);
+        debug!("New TLS connection to {:?} ({})", socket_addr, server_name);
+        Ok(connection_idx)
+    }
+
+    /// Sends a query over a pooled connection, and returns the identifier of
+    /// that connection. Identifiers are never reused, unlike slab indices.
+    pub fn send_query(&mut self,
+                      mio_poll: &mio::Poll,
+                      upstream_server_idx: usize,
+                      socket_addr: SocketAddr,
+                      server_name: &str,
+                      query_packet: &[u8])
+                      -> io::Result<usize> {
+        let pool_len = self.server_connections
+            .get(&upstream_server_idx)
+            .map_or(0, |connections| connections.len());
+        let connection_idx = if pool_len < UPSTREAM_TLS_CONNECTIONS_PER_SERVER {
+            try!(self.connect(mio_poll, upstream_server_idx, socket_addr, server_name))
+        } else {
+            self.next = self.next.wrapping_add(1);
+            self.server_connections[&upstream_server_idx][self.next % pool_len]
+        };
+        if let Err(e) = self.connections[connection_idx].send_query(query_packet) {
+            self.close(mio_poll, connection_idx);
+            return Err(e);
+        }
+        self.reregister(mio_poll, connection_idx);
+        Ok(self.connections[connection_idx].id)
+    }
+
+    pub fn ready(&mut self,
+                 mio_poll: &mio::Poll,
+                 connection_idx: usize,
+                 events: Ready)
+                 -> Option<(usize, SocketAddr, Vec<Vec<u8>>)> {
+        let res = {
+            let connection = match self.connections.get_mut(connection_idx) {
+                None => {
+                    debug!("Event for a nonexistent upstream TLS connection");
+                    return None;
+                }
+                Some(connection) => connection,
+            };
+            if events.is_error() {
+                Err(io::Error::new(io::ErrorKind::Other, "Upstream TLS connection error"))
+            } else {
+                let mut res = Ok(vec![]);
+                if events.is_readable() || events.is_hup() {
+                    res = connection.read_responses();
+                }
+                if res.is_ok() {
+                    if let Err(e) = connection.flush() {
+                        res = Err(e);
+                    }
+                }
+                res.map(|responses| {
+                    (connection.upstream_server_idx,
+                     connection.socket_addr,
+                     responses,
+                     connection.closed)
+                })
+            }
+        };
+        match res {
+            Err(e) => {
+                info!("Upstream TLS connection failed: {}", e);
+                self.close(mio_poll, connection_idx);
+                None
+            }
+            Ok((upstream_server_idx, socket_addr, responses, closed)) => {
+                if closed {
+                    debug!("Upstream TLS connection to {:?} closed", socket_addr);
+                    self.close(mio_poll, connection_idx);
+                } else {
+                    self.reregister(mio_poll, connection_idx);
+                }
+                Some((upstream_server_idx, socket_addr, responses))
+            }
+        }
+    }
+
+    fn reregister(&self, mio_poll: &mio::Poll, connection_idx: usize) {
+        let connection = &self.connections[connection_idx];
+        mio_poll.reregister(&connection.tcp_stream,
+                        Token(UPSTREAM_TLS_TOK_BASE + connection_idx),
+                        connection.interest(),
+                        PollOpt::edge() | PollOpt::oneshot())
+            .expect("Unable to reregister an upstream TLS connection");
+    }
+
+    pub fn remap(&mut self, mio_poll: &mio::Poll, new_idxs: &[usize]) {
+        let server_connections = mem::replace(&mut self.server_connections, HashMap::new());
+        for (upstream_server_idx, connection_idxs) in server_connections {
+            let new_idx = new_idxs.get(upstream_server_idx).cloned().unwrap_or(usize::MAX);
+            if new_idx == usize::MAX {
+                for connection_idx in connection_idxs {
+                    self.close(mio_poll, connection_idx);
+                }
+                continue;
+            }
+            for &connection_idx in &connection_idxs {
+                self.connections[connection_idx].upstream_server_idx = new_idx;
+            }
+            self.server_connections.insert(new_idx, connection_idxs);
+        }
+    }
+
+    pub fn close_idle(&mut self, mio_poll: &mio::Poll) {
+        let idle_timeout = Duration::from_millis(UPSTREAM_TLS_IDLE_TIMEOUT_MS);
+        let idle: Vec<usize> = self.server_connections
+            .values()
+            .flat_map(|connection_idxs| connection_idxs.iter().cloned())
+            .filter(|&connection_idx| {
+                self.connections[connection_idx].last_used.elapsed() >= idle_timeout
+            })
+            .collect();
+        for connection_idx in idle {
+            debug!("Closing an idle upstream TLS connection to {:?}",
+                   self.connections[connection_idx].socket_addr);
+            self.close(mio_poll, connection_idx);
+        }
+    }
+
+    /// Returns the identifiers of the connections closed since the last call.
+    pub fn take_closed(&mut self) -> Vec<usize> {
+        mem::replace(&mut self.closed, Vec::new())
+    }
+
+    fn close(&mut self, mio_poll: &mio::Poll, connection_idx: usize) {
+        let connection = match self.connections.remove(connection_idx) {
+            None => return,
+            Some(connection) => connection,
+        };
+        let _ = mio_poll.deregister(&connection.tcp_stream);
+        self.closed.push(connection.id);
+        if let Some(connections) = self.server_connections
+            .get_mut(&connection.upstream_server_idx) {
+            connections.retain(|&idx| idx != connection_idx);
+        }
+    }
+}
@@ -1,5 +1,14 @@
-use prometheus::{Counter, Gauge};
-use std::time::Instant;
+use cache::CacheDecision;
+use dns;
+use prometheus::{Counter, CounterVec, Gauge, GaugeVec, HistogramVec};
+use std::time::{Duration, Instant};
+
+const LATENCY_BUCKETS: &'static [f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+                                          0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+const RCODE_NAMES: &'static [&'static str] = &["NOERROR", "SERVFAIL", "NXDOMAIN", "REFUSED",
+                                               "other"];
+const QTYPE_NAMES: &'static [&'static str] = &["A", "AAAA", "MX", "TXT", "ANY", "other"];
 
 pub struct StartInstant(pub Instant);
 
@@ -14,12 +23,33 @@ pub struct Varz {
     pub client_queries: Gauge,
     pub client_queries_udp: Counter,
     pub client_queries_tcp: Counter,
+    pub client_queries_tls: Counter,
+    pub client_queries_https: Counter,
     pub client_queries_cached: Counter,
     pub client_queries_expired: Counter,
+    pub client_queries_stale: Counter,
+    pub client_queries_prefetched: Counter,
     pub client_queries_errors: Counter,
+    pub client_queries_denied: Counter,
+    pub client_queries_rate_limited: Counter,
+    pub client_tcp_connections_rejected: Counter,
+    pub client_responses_rcode: Vec<Counter>,
+    pub client_responses_qtype: Vec<Counter>,
     pub upstream_errors: Counter,
     pub upstream_received: Counter,
     pub upstream_timeout: Counter,
+    pub upstream_truncated: Counter,
+    pub rrl_dropped: Counter,
+    pub rrl_slipped: Counter,
+    pub query_log_dropped: Counter,
+    pub udp_listener_queries: CounterVec,
+    pub client_latency: HistogramVec,
+    pub upstream_rtt: HistogramVec,
+    pub upstream_server_sent: CounterVec,
+    pub upstream_server_received: CounterVec,
+    pub upstream_server_timeout: CounterVec,
+    pub upstream_server_failures: CounterVec,
+    pub upstream_server_online: GaugeVec,
 }
 
 impl Varz {
@@ -67,6 +97,16 @@ impl Varz {
                                                          using TCP",
                                                         labels!{"handler" => "all",}))
                 .unwrap(),
+            client_queries_tls: register_counter!(opts!("edgedns_client_queries_tls",
+                                                        "Number of client queries received \
+                                                         using DNS-over-TLS",
+                                                        labels!{"handler" => "all",}))
+                .unwrap(),
+            client_queries_https: register_counter!(opts!("edgedns_client_queries_https",
+                                                          "Number of client queries received \
+                                                           using DNS-over-HTTPS",
+                                                          labels!{"handler" => "all",}))
+                .unwrap(),
             client_queries_cached: register_counter!(opts!("edgedns_client_queries_cached",
                                                            "Number of client queries sent from \
                                                             the cache",
@@ -76,10 +116,53 @@ impl Varz {
                                                             "Number of expired client queries",
                                                             labels!{"handler" => "all",}))
                 .unwrap(),
+            client_queries_stale: register_counter!(opts!("edgedns_client_queries_stale",
+                                                          "Number of client queries answered \
+                                                           with stale data",
+                                                          labels!{"handler" => "all",}))
+                .unwrap(),
+            client_queries_prefetched: register_counter!(opts!("edgedns_client_queries_prefetched",
+                                                               "Number of client queries that \
+                                                                triggered a prefetch",
+                                                               labels!{"handler" => "all",}))
+                .unwrap(),
             client_queries_errors: register_counter!(opts!("edgedns_client_queries_errors",
                                                            "Number of bogus client queries",
                                                            labels!{"handler" => "all",}))
                 .unwrap(),
+            client_queries_denied: register_counter!(opts!("edgedns_client_queries_denied",
+                                                           "Number of client queries denied by \
+                                                            the access control lists",
+                                                           labels!{"handler" => "all",}))
+                .unwrap(),
+            client_queries_rate_limited:
+                register_counter!(opts!("edgedns_client_queries_rate_limited",
+                                        "Number of client queries rejected by the per-client \
+                                         rate limit",
+                                        labels!{"handler" => "all",}))
+                .unwrap(),
+            client_tcp_connections_rejected:
+                register_counter!(opts!("edgedns_client_tcp_connections_rejected",
+                                        "Number of TCP connections rejected by the per-client \
+                                         connections limit",
+                                        labels!{"handler" => "all",}))
+                .unwrap(),
+            client_responses_rcode:
+                label_counters(register_counter_vec!(opts!("edgedns_client_responses_rcode",
+                                                           "Number of responses sent to \
+                                                            clients, by response code",
+                                                           labels!{"handler" => "all",}),
+                                                     &["rcode"])
+                                   .unwrap(),
+                               RCODE_NAMES),
+            client_responses_qtype:
+                label_counters(register_counter_vec!(opts!("edgedns_client_responses_qtype",
+                                                           "Number of responses sent to \
+                                                            clients, by query type",
+                                                           labels!{"handler" => "all",}),
+                                                     &["qtype"])
+                                   .unwrap(),
+                               QTYPE_NAMES),
             upstream_errors: register_counter!(opts!("edgedns_upstream_errors",
                                                      "Number of bogus upstream servers responses",
                                                      labels!{"handler" => "all",}))
@@ -93,8 +176,130 @@ impl Varz {
                                                        having timed out",
                                                       labels!{"handler" => "all",}))
                 .unwrap(),
+            upstream_truncated: register_counter!(opts!("edgedns_upstream_truncated",
+                                                        "Number of truncated upstream servers \
+                                                         responses retried using TCP",
+                                                        labels!{"handler" => "all",}))
+                .unwrap(),
+            rrl_dropped: register_counter!(opts!("edgedns_rrl_dropped",
+                                                 "Number of responses dropped by the response \
+                                                  rate limiter",
+                                                 labels!{"handler" => "all",}))
+                .unwrap(),
+            rrl_slipped: register_counter!(opts!("edgedns_rrl_slipped",
+                                                 "Number of rate limited responses replaced \
+                                                  with a truncated response",
+                                                 labels!{"handler" => "all",}))
+                .unwrap(),
+            query_log_dropped: register_counter!(opts!("edgedns_query_log_dropped",
+                                                       "Number of query log entries dropped \
+                                                        because the queue was full",
+                                                       labels!{"handler" => "all",}))
+                .unwrap(),
+            udp_listener_queries: register_counter_vec!(opts!("edgedns_udp_listener_queries",
+                                                              "Number of client queries \
+                                                               received by each UDP listener \
+                                                               thread",
+                                                              labels!{"handler" => "all",}),
+                                                        &["listen", "thread"])
+                .unwrap(),
+            client_latency:
+                register_histogram_vec!(histogram_opts!("edgedns_client_latency_seconds",
+                                                        "Time to respond to client queries, in \
+                                                         seconds",
+                                                        LATENCY_BUCKETS.to_vec())
+                                            .const_labels(labels!{"handler" => "all",}),
+                                        &["protocol", "cache"])
+                .unwrap(),
+            upstream_rtt:
+                register_histogram_vec!(histogram_opts!("edgedns_upstream_rtt_seconds",
+                                                        "Time for upstream servers to respond, \
+                                                         in seconds",
+                                                        LATENCY_BUCKETS.to_vec())
+                                            .const_labels(labels!{"handler" => "all",}),
+                                        &["upstream"])
+                .unwrap(),
+            upstream_server_sent: register_counter_vec!(opts!("edgedns_upstream_server_sent",
+                                                              "Number of queries sent to each \
+                                                               upstream server",
+                                                              labels!{"handler" => "all",}),
+                                                        &["upstream"])
+                .unwrap(),
+            upstream_server_received:
+                register_counter_vec!(opts!("edgedns_upstream_server_received",
+                                            "Number of responses received from each upstream \
+                                             server",
+                                            labels!{"handler" => "all",}),
+                                      &["upstream"])
+                .unwrap(),
+            upstream_server_timeout:
+                register_counter_vec!(opts!("edgedns_upstream_server_timeout",
+                                            "Number of queries to each upstream server having \
+                                             timed out",
+                                            labels!{"handler" => "all",}),
+                                      &["upstream"])
+                .unwrap(),
+            upstream_server_failures:
+                register_counter_vec!(opts!("edgedns_upstream_server_failures",
+                                            "Number of queries that couldn't be sent to each \
+                                             upstream server, and of bogus responses",
+                                            labels!{"handler" => "all",}),
+                                      &["upstream"])
+                .unwrap(),
+            upstream_server_online:
+                register_gauge_vec!(opts!("edgedns_upstream_server_online",
+                                          "Whether each upstream server is online (1) or \
+                                           offline (0)",
+                                          labels!{"handler" => "all",}),
+                                    &["upstream"])
+                .unwrap(),
         }
     }
+
+    pub fn client_cache_lookup(&self, cache_decision: CacheDecision) {
+        match cache_decision {
+            CacheDecision::Hit => self.client_queries_cached.inc(),
+            CacheDecision::Prefetch => {
+                self.client_queries_prefetched.inc();
+                self.client_queries_cached.inc();
+            }
+            CacheDecision::Stale => {
+                self.client_queries_stale.inc();
+                self.client_queries_cached.inc();
+            }
+            CacheDecision::Expired => self.client_queries_expired.inc(),
+        }
+    }
+
+    pub fn client_response(&self, qtype: u16, packet: &[u8]) {
+        let rcode_idx = match dns::rcode(packet) {
+            dns::DNS_RCODE_NOERROR => 0,
+            dns::DNS_RCODE_SERVFAIL => 1,
+            dns::DNS_RCODE_NXDOMAIN => 2,
+            dns::DNS_RCODE_REFUSED => 3,
+            _ => 4,
+        };
+        let qtype_idx = match qtype {
+            dns::DNS_TYPE_A => 0,
+            dns::DNS_TYPE_AAAA => 1,
+            dns::DNS_TYPE_MX => 2,
+            dns::DNS_TYPE_TXT => 3,
+            dns::DNS_TYPE_ANY => 4,
+            _ => 5,
+        };
+        self.client_responses_rcode[rcode_idx].inc();
+        self.client_responses_qtype[qtype_idx].inc();
+    }
+}
+
+fn label_counters(counter_vec: CounterVec, label_values: &[&str]) -> Vec<Counter> {
+    label_values.iter()
+        .map(|&label_value| counter_vec.with_label_values(&[label_value]))
+        .collect()
+}
+
+pub fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
 }
 
 impl Default for Varz {
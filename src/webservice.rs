@@ -1,39 +1,111 @@
-use hyper::header::ContentType;
+use cache::{Cache, CacheDecision};
+use client_query::*;
+use dns;
+use dns::NormalizedQuestion;
+use heavy_hitters::HeavyHitters;
+use hyper::header::{CacheControl, CacheDirective, ContentType};
+use hyper::method::Method;
 use hyper::mime::Mime;
 use hyper::server::{Server, Request, Response};
 use hyper::status::StatusCode;
 use hyper::uri::RequestUri::AbsolutePath;
+use mio;
+use mio::*;
 use prometheus::{self, Encoder, TextEncoder};
-use varz::{StartInstant, Varz};
+use query_log::{CacheStatus, QueryLog};
+use resolver::ResolverResponse;
+use varz::{duration_secs, StartInstant, Varz};
+use std::cell::RefCell;
 use std::io;
-use std::sync::Arc;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use std::thread::spawn;
+use std::time::{Duration, Instant};
 
 use super::RPDNSContext;
-use super::WEBSERVICE_THREADS;
+use super::{DNS_QUERY_MAX_SIZE, DNS_QUERY_MIN_SIZE, DOH_TIMEOUT_MS, SERVE_STALE_TTL,
+            WEBSERVICE_THREADS};
+
+const DOH_CONTENT_TYPE: &'static str = "application/dns-message";
+const DOH_PATH: &'static str = "/dns-query";
+const DOH_RESPONSE_TOK: Token = Token(0);
+const HEAVY_HITTERS_PATH: &'static str = "/heavy-hitters";
+
+/// Response channel of a webservice worker thread, reused across DoH requests.
+struct DohChannel {
+    mio_poll: mio::Poll,
+    httpsclient_tx: channel::SyncSender<ResolverResponse>,
+    httpsclient_rx: channel::Receiver<ResolverResponse>,
+}
+
+impl DohChannel {
+    fn new(capacity: usize) -> io::Result<DohChannel> {
+        let mio_poll = try!(mio::Poll::new());
+        let (httpsclient_tx, httpsclient_rx): (channel::SyncSender<ResolverResponse>,
+                                               channel::Receiver<ResolverResponse>) =
+            channel::sync_channel(capacity);
+        try!(mio_poll.register(&httpsclient_rx, DOH_RESPONSE_TOK, Ready::all(), PollOpt::edge()));
+        Ok(DohChannel {
+            mio_poll: mio_poll,
+            httpsclient_tx: httpsclient_tx,
+            httpsclient_rx: httpsclient_rx,
+        })
+    }
+}
+
+thread_local!(static DOH_CHANNEL: RefCell<Option<DohChannel>> = RefCell::new(None));
 
 pub struct WebService {
     varz: Arc<Varz>,
+    cache: Cache,
+    heavy_hitters: HeavyHitters,
+    query_log: Mutex<QueryLog>,
+    resolver_tx: Mutex<channel::SyncSender<ClientQuery>>,
+    max_active_queries: usize,
 }
 
 impl WebService {
-    fn new(rpdns_context: &RPDNSContext) -> WebService {
-        WebService { varz: rpdns_context.varz.clone() }
+    fn new(rpdns_context: &RPDNSContext,
+           resolver_tx: channel::SyncSender<ClientQuery>)
+           -> WebService {
+        WebService {
+            varz: rpdns_context.varz.clone(),
+            cache: rpdns_context.cache.clone(),
+            heavy_hitters: rpdns_context.heavy_hitters.clone(),
+            query_log: Mutex::new(rpdns_context.query_log.clone()),
+            resolver_tx: Mutex::new(resolver_tx),
+            max_active_queries: rpdns_context.config.max_active_queries,
+        }
     }
 
     fn handler(&self, req: Request, mut res: Response) {
-        match req.uri {
-            AbsolutePath(ref path) if path == "/metrics" => path,
+        let path = match req.uri {
+            AbsolutePath(ref path) => path.clone(),
             _ => {
                 *res.status_mut() = StatusCode::NotFound;
                 return;
             }
         };
+        if path == "/metrics" {
+            self.metrics(res)
+        } else if path == HEAVY_HITTERS_PATH {
+            self.heavy_hitters(res)
+        } else if path == DOH_PATH || path.starts_with(&format!("{}?", DOH_PATH)) {
+            self.dns_query(req, res, &path)
+        } else {
+            *res.status_mut() = StatusCode::NotFound;
+        }
+    }
+
+    fn metrics(&self, mut res: Response) {
         let StartInstant(start_instant) = self.varz.start_instant;
         let uptime = start_instant.elapsed().as_secs();
         self.varz.uptime.set(uptime as f64);
         let client_queries = self.varz.client_queries_udp.get() +
-                             self.varz.client_queries_tcp.get();
+                             self.varz.client_queries_tcp.get() +
+                             self.varz.client_queries_tls.get() +
+                             self.varz.client_queries_https.get();
         self.varz.client_queries.set(client_queries);
         let metric_families = prometheus::gather();
         let mut buffer = vec![];
@@ -44,9 +116,181 @@ impl WebService {
         res.send(&buffer).unwrap();
     }
 
-    pub fn spawn(rpdns_context: &RPDNSContext) -> io::Result<()> {
+    fn heavy_hitters(&self, mut res: Response) {
+        let json = self.heavy_hitters.to_json();
+        res.headers_mut().set(ContentType("application/json".parse::<Mime>().unwrap()));
+        let _ = res.send(json.as_bytes());
+    }
+
+    fn dns_query(&self, mut req: Request, mut res: Response, path: &str) {
+        self.varz.client_queries_https.inc();
+        let client_addr = req.remote_addr;
+        let packet = match req.method {
+            Method::Get => {
+                match query_param(path, "dns").and_then(base64url_decode) {
+                    None => {
+                        *res.status_mut() = StatusCode::BadRequest;
+                        return;
+                    }
+                    Some(packet) => packet,
+                }
+            }
+            Method::Post => {
+                let content_type = req.headers.get::<ContentType>().map(|x| format!("{}", x.0));
+                if content_type.as_ref().map(|x| x.as_str()) != Some(DOH_CONTENT_TYPE) {
+                    *res.status_mut() = StatusCode::UnsupportedMediaType;
+                    return;
+                }
+                let mut packet = Vec::with_capacity(DNS_QUERY_MAX_SIZE);
+                let mut body = (&mut req).take(DNS_QUERY_MAX_SIZE as u64 + 1);
+                if body.read_to_end(&mut packet).is_err() {
+                    *res.status_mut() = StatusCode::BadRequest;
+                    return;
+                }
+                packet
+            }
+            _ => {
+                *res.status_mut() = StatusCode::MethodNotAllowed;
+                return;
+            }
+        };
+        if packet.len() < DNS_QUERY_MIN_SIZE || packet.len() > DNS_QUERY_MAX_SIZE {
+            info!("Short or large query using DNS-over-HTTPS");
+            self.varz.client_queries_errors.inc();
+            *res.status_mut() = StatusCode::BadRequest;
+            return;
+        }
+        let normalized_question = match dns::normalize(&packet, true) {
+            Ok(normalized_question) => normalized_question,
+            Err(e) => {
+                debug!("Error while parsing the question: {}", e);
+                self.varz.client_queries_errors.inc();
+                *res.status_mut() = StatusCode::BadRequest;
+                return;
+            }
+        };
+        self.heavy_hitters.record(client_addr.ip(), &normalized_question.qname);
+        let (response, max_age) = match self.resolve(&normalized_question, client_addr) {
+            None => {
+                *res.status_mut() = StatusCode::ServiceUnavailable;
+                return;
+            }
+            Some(response_and_max_age) => response_and_max_age,
+        };
+        res.headers_mut().set(ContentType(DOH_CONTENT_TYPE.parse::<Mime>().unwrap()));
+        res.headers_mut().set(CacheControl(vec![CacheDirective::MaxAge(max_age)]));
+        self.varz.client_response(normalized_question.qtype, &response);
+        let _ = res.send(&response);
+    }
+
+    fn resolve(&self,
+               normalized_question: &NormalizedQuestion,
+               client_addr: SocketAddr)
+               -> Option<(Vec<u8>, u32)> {
+        let ts = Instant::now();
+        let mut cache = self.cache.clone();
+        if let Some((mut cache_entry, cache_decision)) = cache.lookup(normalized_question) {
+            self.varz.client_cache_lookup(cache_decision);
+            if cache_decision.needs_refresh() {
+                let resolver_tx = self.resolver_tx.lock().unwrap().clone();
+                let _ = resolver_tx.send(ClientQuery::refresh(normalized_question));
+            }
+            if cache_decision.is_servable() {
+                debug!("cached");
+                dns::set_tid(&mut cache_entry.packet, normalized_question.tid);
+                dns::overwrite_qname(&mut cache_entry.packet, &normalized_question.qname);
+                let (max_age, cache_status) = if cache_decision == CacheDecision::Stale {
+                    (SERVE_STALE_TTL, CacheStatus::Stale)
+                } else {
+                    (remaining_ttl(cache_entry.expiration), CacheStatus::Hit)
+                };
+                self.varz
+                    .client_latency
+                    .with_label_values(&[ClientQueryProtocol::HTTPS.name(), "hit"])
+                    .observe(duration_secs(ts.elapsed()));
+                self.query_log.lock().unwrap().log(client_addr.ip(),
+                                                   ClientQueryProtocol::HTTPS,
+                                                   normalized_question,
+                                                   &cache_entry.packet,
+                                                   cache_status,
+                                                   ts);
+                return Some((cache_entry.packet, max_age));
+            }
+            debug!("expired");
+        }
+        DOH_CHANNEL.with(|doh_channel| {
+            let mut doh_channel = doh_channel.borrow_mut();
+            if doh_channel.is_none() {
+                *doh_channel = DohChannel::new(self.max_active_queries).ok();
+            }
+            match doh_channel.as_ref() {
+                None => None,
+                Some(doh_channel) => {
+                    self.resolve_upstream(doh_channel, normalized_question, client_addr)
+                }
+            }
+        })
+    }
+
+    fn resolve_upstream(&self,
+                        doh_channel: &DohChannel,
+                        normalized_question: &NormalizedQuestion,
+                        client_addr: SocketAddr)
+                        -> Option<(Vec<u8>, u32)> {
+        while doh_channel.httpsclient_rx.try_recv().is_ok() {
+            debug!("Discarding a late DNS-over-HTTPS response");
+        }
+        let client_query = ClientQuery {
+            proto: ClientQueryProtocol::HTTPS,
+            client_addr: Some(client_addr),
+            udp_socket_idx: None,
+            client_tok: Some(DOH_RESPONSE_TOK),
+            tcpclient_tx: Some(doh_channel.httpsclient_tx.clone()),
+            normalized_question: normalized_question.clone(),
+            ts: Instant::now(),
+        };
+        let resolver_tx = self.resolver_tx.lock().unwrap().clone();
+        if resolver_tx.send(client_query).is_err() {
+            return None;
+        }
+        let normalized_question_minimal = normalized_question.minimal();
+        let deadline = Instant::now() + Duration::from_millis(DOH_TIMEOUT_MS);
+        let mut events = mio::Events::with_capacity(1);
+        loop {
+            while let Ok(resolver_response) = doh_channel.httpsclient_rx.try_recv() {
+                let mut packet = resolver_response.response;
+                if packet.len() < DNS_QUERY_MIN_SIZE {
+                    return None;
+                }
+                let matches = resolver_response.dnssec == normalized_question.dnssec &&
+                              dns::normalize(&packet, false)
+                    .map(|x| x.minimal() == normalized_question_minimal)
+                    .unwrap_or(false);
+                if !matches {
+                    debug!("Discarding a late DNS-over-HTTPS response");
+                    continue;
+                }
+                dns::set_tid(&mut packet, normalized_question.tid);
+                return Some((packet, resolver_response.ttl));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                debug!("Timeout while waiting for a DNS-over-HTTPS response");
+                return None;
+            }
+            match doh_channel.mio_poll.poll(&mut events, Some(deadline.duration_since(now))) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return None,
+                _ => {}
+            }
+        }
+    }
+
+    pub fn spawn(rpdns_context: &RPDNSContext,
+                 resolver_tx: channel::SyncSender<ClientQuery>)
+                 -> io::Result<()> {
         let listen_addr = rpdns_context.config.webservice_listen_addr.to_owned();
-        let web_service = WebService::new(rpdns_context);
+        let web_service = WebService::new(rpdns_context, resolver_tx);
         spawn(move || {
             let mut server = Server::http(&*listen_addr).expect("Unable to spawn the webservice");
             server.keep_alive(None);
@@ -60,3 +304,100 @@ impl WebService {
         Ok(())
     }
 }
+
+fn remaining_ttl(expiration: Instant) -> u32 {
+    let now = Instant::now();
+    if now >= expiration {
+        return 0;
+    }
+    expiration.duration_since(now).as_secs() as u32
+}
+
+fn query_param<'t>(path: &'t str, name: &str) -> Option<&'t str> {
+    let query = match path.find('?') {
+        None => return None,
+        Some(offset) => &path[offset + 1..],
+    };
+    query.split('&')
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) if key == name => Some(value),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn base64url_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_right_matches('=');
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut acc_len = 0;
+    for c in encoded.bytes() {
+        let value = match c {
+            b'A'...b'Z' => c - b'A',
+            b'a'...b'z' => c - b'a' + 26,
+            b'0'...b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = ((acc << 6) | value as u32) & 0xffff;
+        acc_len += 6;
+        if acc_len >= 8 {
+            acc_len -= 8;
+            decoded.push((acc >> acc_len) as u8);
+        }
+    }
+    if acc_len >= 6 {
+        return None;
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64url_decode, query_param};
+
+    #[test]
+    fn test_base64url_decode() {
+        assert_eq!(base64url_decode(""), Some(vec![]));
+        assert_eq!(base64url_decode("Zg"), Some(b"f".to_vec()));
+        assert_eq!(base64url_decode("Zm8"), Some(b"fo".to_vec()));
+        assert_eq!(base64url_decode("Zm9v"), Some(b"foo".to_vec()));
+        assert_eq!(base64url_decode("Zm9vYg"), Some(b"foob".to_vec()));
+        assert_eq!(base64url_decode("-_8"), Some(vec![0xfb, 0xff]));
+        let packet = base64url_decode("AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB").unwrap();
+        assert_eq!(packet.len(), 33);
+        assert_eq!(&packet[..4], &[0x00, 0x00, 0x01, 0x00]);
+        assert_eq!(&packet[29..], &[0x00, 0x01, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_base64url_decode_padding() {
+        assert_eq!(base64url_decode("Zg=="), Some(b"f".to_vec()));
+        assert_eq!(base64url_decode("Zm8="), Some(b"fo".to_vec()));
+        assert_eq!(base64url_decode("Zg=x"), None);
+        assert_eq!(base64url_decode("Z"), None);
+        assert_eq!(base64url_decode("Zm9vY"), None);
+        assert_eq!(base64url_decode("Zm9vY==="), None);
+    }
+
+    #[test]
+    fn test_base64url_decode_invalid_alphabet() {
+        assert_eq!(base64url_decode("Zm9v+"), None);
+        assert_eq!(base64url_decode("Zm9v/"), None);
+        assert_eq!(base64url_decode("Zm 9v"), None);
+        assert_eq!(base64url_decode("Zm9v%3D"), None);
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(query_param("/dns-query?dns=AAAB", "dns"), Some("AAAB"));
+        assert_eq!(query_param("/dns-query?ct=x&dns=AAAB", "dns"), Some("AAAB"));
+        assert_eq!(query_param("/dns-query?dnsx=AAAB", "dns"), None);
+        assert_eq!(query_param("/dns-query?dns", "dns"), None);
+        assert_eq!(query_param("/dns-query", "dns"), None);
+    }
+}